@@ -25,6 +25,7 @@ rustPlatform.buildRustPackage {
 
   nativeBuildInputs = [
     pkg-config
+    glib
     wrapGAppsHook4
   ];
 
@@ -37,6 +38,11 @@ rustPlatform.buildRustPackage {
     polkit
   ];
 
+  postInstall = ''
+    install -Dm644 data/dev.badged.gschema.xml -t $out/share/gsettings-schemas/$name/glib-2.0/schemas
+    glib-compile-schemas $out/share/gsettings-schemas/$name/glib-2.0/schemas
+  '';
+
   meta = with lib; {
     description = "A polkit authentication agent for Linux window managers";
     homepage = "https://github.com/jfernandez/badged";
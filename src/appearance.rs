@@ -0,0 +1,166 @@
+//! Whether the dialog should look dark.
+//!
+//! Desktops publish the user's light/dark preference as `color-scheme` on
+//! the XDG settings portal, which plain GTK4 doesn't read. This follows it,
+//! asks the GTK theme for its dark variant to match, and falls back to the
+//! theme itself (`Adwaita-dark` and the like) when there is no preference.
+//! The `theme-variant` GSetting can pin either style instead.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use gtk4::gio;
+use gtk4::glib;
+use gtk4::prelude::*;
+
+const PORTAL_NAME: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const SETTINGS_INTERFACE: &str = "org.freedesktop.portal.Settings";
+const APPEARANCE_NAMESPACE: &str = "org.freedesktop.appearance";
+const COLOR_SCHEME_KEY: &str = "color-scheme";
+
+/// `color-scheme` values defined by the portal; 0 is no preference.
+const PREFER_DARK: u32 = 1;
+const PREFER_LIGHT: u32 = 2;
+
+/// Keeps the portal subscription alive; dropping it stops following.
+pub struct Watch {
+    _proxy: Option<gio::DBusProxy>,
+    _settings: Option<gio::Settings>,
+}
+
+/// Call `on_change` with whether the dialog should be dark, now and
+/// whenever the preference, the theme or `settings` change.
+pub fn watch(settings: Option<gio::Settings>, on_change: impl Fn(bool) + 'static) -> Watch {
+    let on_change = Rc::new(on_change);
+    let scheme = Rc::new(Cell::new(None));
+
+    let proxy = match gio::DBusProxy::for_bus_sync(
+        gio::BusType::Session,
+        gio::DBusProxyFlags::DO_NOT_LOAD_PROPERTIES,
+        None,
+        PORTAL_NAME,
+        PORTAL_PATH,
+        SETTINGS_INTERFACE,
+        gio::Cancellable::NONE,
+    ) {
+        Ok(proxy) => Some(proxy),
+        Err(err) => {
+            log!("[appearance] Not following the color scheme: {err}");
+            None
+        }
+    };
+
+    if let Some(proxy) = &proxy {
+        scheme.set(read_color_scheme(proxy));
+        let scheme = scheme.clone();
+        let variant = settings.clone();
+        let on_change = on_change.clone();
+        // gio-rs has no typed binding for this signal.
+        proxy.connect_local("g-signal", false, move |values| {
+            let signal = values[2].get::<String>().ok()?;
+            let params = values[3].get::<glib::Variant>().ok()?;
+            if signal != "SettingChanged" {
+                return None;
+            }
+            let (namespace, key, value) = params.get::<(String, String, glib::Variant)>()?;
+            if namespace == APPEARANCE_NAMESPACE && key == COLOR_SCHEME_KEY {
+                scheme.set(color_scheme(&value));
+                apply(scheme.get(), variant.as_ref(), &*on_change);
+            }
+            None
+        });
+    }
+
+    if let Some(gtk_settings) = gtk4::Settings::default() {
+        let scheme = scheme.clone();
+        let variant = settings.clone();
+        let on_change = on_change.clone();
+        gtk_settings.connect_gtk_theme_name_notify(move |_| {
+            apply(scheme.get(), variant.as_ref(), &*on_change)
+        });
+    }
+
+    if let Some(settings) = &settings {
+        let scheme = scheme.clone();
+        let on_change = on_change.clone();
+        settings.connect_changed(Some("theme-variant"), move |settings, _| {
+            apply(scheme.get(), Some(settings), &*on_change)
+        });
+    }
+
+    apply(scheme.get(), settings.as_ref(), &*on_change);
+    Watch {
+        _proxy: proxy,
+        _settings: settings,
+    }
+}
+
+/// Ask the theme for the variant `scheme` prefers, unless `theme-variant`
+/// pins one, and report the result.
+fn apply(scheme: Option<u32>, variant: Option<&gio::Settings>, on_change: &dyn Fn(bool)) {
+    let pinned = variant.map(|variant| variant.string("theme-variant"));
+    let scheme = match pinned.as_deref() {
+        Some("light") => Some(PREFER_LIGHT),
+        Some("dark") => Some(PREFER_DARK),
+        _ => scheme,
+    };
+    if let Some(dark) = request_scheme(scheme) {
+        on_change(dark);
+    }
+}
+
+/// Ask the GTK theme for the variant `scheme` prefers and report whether
+/// the dialog ends up dark.
+#[cfg(not(feature = "adwaita"))]
+fn request_scheme(scheme: Option<u32>) -> Option<bool> {
+    let settings = gtk4::Settings::default()?;
+    // Without a portal, leave whatever settings.ini asked for alone.
+    if let Some(scheme) = scheme {
+        settings.set_gtk_application_prefer_dark_theme(scheme == PREFER_DARK);
+    }
+    let dark_theme = settings
+        .gtk_theme_name()
+        .is_some_and(|name| name.to_lowercase().ends_with("-dark"));
+    Some(settings.is_gtk_application_prefer_dark_theme() || dark_theme)
+}
+
+/// libadwaita ignores GtkSettings' dark preference (and warns about it), so
+/// the request goes to its style manager, which otherwise follows the
+/// portal on its own.
+#[cfg(feature = "adwaita")]
+fn request_scheme(scheme: Option<u32>) -> Option<bool> {
+    let manager = libadwaita::StyleManager::default();
+    manager.set_color_scheme(match scheme {
+        Some(PREFER_DARK) => libadwaita::ColorScheme::ForceDark,
+        Some(PREFER_LIGHT) => libadwaita::ColorScheme::ForceLight,
+        _ => libadwaita::ColorScheme::Default,
+    });
+    Some(manager.is_dark())
+}
+
+/// The current `color-scheme`, or `None` without a portal that knows it.
+/// `ReadOne` is the current method; older portals only have `Read`.
+fn read_color_scheme(proxy: &gio::DBusProxy) -> Option<u32> {
+    let args = (APPEARANCE_NAMESPACE, COLOR_SCHEME_KEY).to_variant();
+    let reply = ["ReadOne", "Read"].iter().find_map(|method| {
+        proxy
+            .call_sync(
+                method,
+                Some(&args),
+                gio::DBusCallFlags::NONE,
+                -1,
+                gio::Cancellable::NONE,
+            )
+            .ok()
+    })?;
+    color_scheme(&reply.child_value(0))
+}
+
+/// `Read` wraps the value in an extra variant, so unwrap until the number.
+fn color_scheme(value: &glib::Variant) -> Option<u32> {
+    match value.get::<u32>() {
+        Some(scheme) => Some(scheme),
+        None => color_scheme(&value.as_variant()?),
+    }
+}
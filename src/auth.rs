@@ -0,0 +1,590 @@
+//! One request's authentication conversation, as a state machine.
+//!
+//! libpolkit-agent runs the helper and reports what it says through
+//! `PolkitAgentSession` signals. The listener feeds those in here as events
+//! and acts on what comes back, so the rules (which answer is accepted,
+//! when an identity may be switched, what a failure leads to) can be tested
+//! without a session, a bus or a display.
+
+use std::time::{Duration, Instant};
+
+/// Where the conversation stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// An attempt was started and its helper hasn't said anything yet.
+    Starting,
+    /// The helper is talking (a fingerprint scan, a key to touch) without
+    /// waiting on the user.
+    Biometric,
+    /// The helper asked prompt `id` and waits for the answer.
+    PasswordPrompt(u64),
+    /// An answer was handed over and the helper hasn't reacted yet.
+    Verifying,
+    /// Input is disabled after too many failed attempts.
+    LockedOut,
+    /// The request was answered, one way or the other.
+    Done,
+}
+
+/// Limits on failed attempts, from the config.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Failed attempts before locking out or giving up; 0 is unlimited.
+    pub max_attempts: u32,
+    /// How long the one lockout lasts; zero gives up instead.
+    pub lockout: Duration,
+}
+
+/// How an attempt ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completion {
+    Succeeded,
+    /// It ended before the helper said a word, so it never ran and trying
+    /// again would only loop.
+    NeverRan,
+    /// PAM said no. `counted` is false when the user can fix the cause (the
+    /// smartcard was pulled), so it doesn't count against `max_attempts`.
+    Failed {
+        counted: bool,
+        next: Next,
+    },
+}
+
+/// What follows a failed attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Next {
+    Retry,
+    LockOut(Duration),
+    GiveUp,
+}
+
+#[derive(Debug)]
+pub struct Conversation {
+    state: State,
+    /// Id of the live attempt. Signals from older ones are ignored.
+    attempt_id: u64,
+    /// Id of the latest prompt, counted across the request's attempts.
+    prompt_id: u64,
+    /// Failed attempts since the request was shown or the last lockout.
+    failed_attempts: u32,
+    /// The live attempt reported an error the user can fix.
+    recoverable_failure: bool,
+    /// A lockout already happened; the next exhausted budget gives up.
+    had_lockout: bool,
+    /// Last time the helper said something or was given an answer.
+    last_activity: Instant,
+}
+
+impl Conversation {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            state: State::Starting,
+            attempt_id: 1,
+            prompt_id: 0,
+            failed_attempts: 0,
+            recoverable_failure: false,
+            had_lockout: false,
+            last_activity: now,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn attempt_id(&self) -> u64 {
+        self.attempt_id
+    }
+
+    pub fn is_locked_out(&self) -> bool {
+        self.state == State::LockedOut
+    }
+
+    /// Whether another identity may take over. Not while an answer is being
+    /// verified, so the old helper can't consume a password meant for the
+    /// new one, and not during a lockout.
+    pub fn can_switch_identity(&self) -> bool {
+        !matches!(
+            self.state,
+            State::Verifying | State::LockedOut | State::Done
+        )
+    }
+
+    /// Retire the live attempt, e.g. to switch identity or replace a stalled
+    /// helper, so nothing it says from here on counts. Returns the id of
+    /// the attempt that replaces it.
+    pub fn retire_attempt(&mut self) -> u64 {
+        self.attempt_id += 1;
+        self.attempt_id
+    }
+
+    /// A helper was started for the live attempt. Ends any lockout.
+    pub fn attempt_started(&mut self, now: Instant) {
+        self.state = State::Starting;
+        self.recoverable_failure = false;
+        self.last_activity = now;
+    }
+
+    /// The helper asked a question. Returns the id its answer must carry.
+    pub fn prompt(&mut self, now: Instant) -> u64 {
+        self.prompt_id += 1;
+        self.state = State::PasswordPrompt(self.prompt_id);
+        self.last_activity = now;
+        self.prompt_id
+    }
+
+    /// The helper showed an info or error message. `recoverable` marks an
+    /// error the user can fix without the attempt counting as failed.
+    pub fn message(&mut self, now: Instant, recoverable: bool) {
+        if self.state == State::Starting {
+            self.state = State::Biometric;
+        }
+        self.recoverable_failure |= recoverable;
+        self.last_activity = now;
+    }
+
+    /// Accept an answer to `prompt_id`. Refused unless it is the prompt being
+    /// waited on, so an answer can't land on a later prompt.
+    pub fn respond(&mut self, prompt_id: u64, now: Instant) -> bool {
+        if self.state != State::PasswordPrompt(prompt_id) {
+            return false;
+        }
+        self.state = State::Verifying;
+        self.last_activity = now;
+        true
+    }
+
+    /// The live attempt ended. After a counted failure the attempt is
+    /// retired; `limits` decide whether another one follows.
+    pub fn completed(&mut self, gained_auth: bool, limits: Limits, now: Instant) -> Completion {
+        if gained_auth {
+            self.state = State::Done;
+            return Completion::Succeeded;
+        }
+        if self.state == State::Starting {
+            self.state = State::Done;
+            return Completion::NeverRan;
+        }
+
+        let counted = !std::mem::take(&mut self.recoverable_failure);
+        if counted {
+            self.failed_attempts += 1;
+        }
+        self.attempt_id += 1;
+        self.last_activity = now;
+
+        let next = if limits.max_attempts == 0 || self.failed_attempts < limits.max_attempts {
+            self.state = State::Starting;
+            Next::Retry
+        } else if !limits.lockout.is_zero() && !self.had_lockout {
+            self.state = State::LockedOut;
+            self.failed_attempts = 0;
+            self.had_lockout = true;
+            Next::LockOut(limits.lockout)
+        } else {
+            self.state = State::Done;
+            Next::GiveUp
+        };
+        Completion::Failed { counted, next }
+    }
+
+    /// Whether the helper is waiting on the user rather than the other way
+    /// round, for the watchdog.
+    pub fn waiting_on_user(&self) -> bool {
+        matches!(self.state, State::PasswordPrompt(_))
+    }
+
+    /// How long the helper has gone without saying anything or being given
+    /// an answer.
+    pub fn silent_for(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_activity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE_TRY: Limits = Limits {
+        max_attempts: 1,
+        lockout: Duration::ZERO,
+    };
+
+    fn answered(conversation: &mut Conversation, now: Instant) {
+        let prompt_id = conversation.prompt(now);
+        assert!(conversation.respond(prompt_id, now));
+    }
+
+    #[test]
+    fn answers_only_the_prompt_being_waited_on() {
+        let now = Instant::now();
+        let mut conversation = Conversation::new(now);
+        assert!(!conversation.respond(1, now));
+
+        let first = conversation.prompt(now);
+        let second = conversation.prompt(now);
+        assert!(!conversation.respond(first, now));
+        assert!(conversation.respond(second, now));
+        assert_eq!(conversation.state(), State::Verifying);
+        // The same answer can't be given twice.
+        assert!(!conversation.respond(second, now));
+    }
+
+    #[test]
+    fn chained_prompts_get_their_own_ids() {
+        let now = Instant::now();
+        let mut conversation = Conversation::new(now);
+        let current = conversation.prompt(now);
+        assert!(conversation.respond(current, now));
+        let new = conversation.prompt(now);
+        assert!(conversation.respond(new, now));
+        let retype = conversation.prompt(now);
+        assert_eq!((current, new, retype), (1, 2, 3));
+        assert_eq!(conversation.state(), State::PasswordPrompt(3));
+    }
+
+    #[test]
+    fn messages_before_a_prompt_are_a_biometric_step() {
+        let now = Instant::now();
+        let mut conversation = Conversation::new(now);
+        conversation.message(now, false);
+        assert_eq!(conversation.state(), State::Biometric);
+        assert!(!conversation.waiting_on_user());
+        conversation.prompt(now);
+        assert!(conversation.waiting_on_user());
+    }
+
+    #[test]
+    fn identity_stays_put_while_an_answer_is_verified() {
+        let now = Instant::now();
+        let mut conversation = Conversation::new(now);
+        assert!(conversation.can_switch_identity());
+        answered(&mut conversation, now);
+        assert!(!conversation.can_switch_identity());
+        conversation.prompt(now);
+        assert!(conversation.can_switch_identity());
+    }
+
+    #[test]
+    fn retired_attempts_are_replaced() {
+        let now = Instant::now();
+        let mut conversation = Conversation::new(now);
+        answered(&mut conversation, now);
+        assert_eq!(conversation.retire_attempt(), 2);
+        conversation.attempt_started(now);
+        assert_eq!(conversation.attempt_id(), 2);
+        assert_eq!(conversation.state(), State::Starting);
+    }
+
+    #[test]
+    fn success_ends_the_conversation() {
+        let now = Instant::now();
+        let mut conversation = Conversation::new(now);
+        answered(&mut conversation, now);
+        assert_eq!(
+            conversation.completed(true, ONE_TRY, now),
+            Completion::Succeeded
+        );
+        assert_eq!(conversation.state(), State::Done);
+        assert!(!conversation.can_switch_identity());
+    }
+
+    #[test]
+    fn a_silent_helper_never_ran() {
+        let now = Instant::now();
+        let mut conversation = Conversation::new(now);
+        assert_eq!(
+            conversation.completed(false, ONE_TRY, now),
+            Completion::NeverRan
+        );
+    }
+
+    #[test]
+    fn one_try_gives_up_on_the_first_failure() {
+        let now = Instant::now();
+        let mut conversation = Conversation::new(now);
+        answered(&mut conversation, now);
+        assert_eq!(
+            conversation.completed(false, ONE_TRY, now),
+            Completion::Failed {
+                counted: true,
+                next: Next::GiveUp
+            }
+        );
+        assert_eq!(conversation.state(), State::Done);
+    }
+
+    #[test]
+    fn retries_then_locks_out_once_then_gives_up() {
+        let limits = Limits {
+            max_attempts: 2,
+            lockout: Duration::from_secs(30),
+        };
+        let now = Instant::now();
+        let mut conversation = Conversation::new(now);
+        let fail = |conversation: &mut Conversation| {
+            conversation.attempt_started(now);
+            answered(conversation, now);
+            match conversation.completed(false, limits, now) {
+                Completion::Failed { next, .. } => next,
+                other => panic!("unexpected {other:?}"),
+            }
+        };
+
+        assert_eq!(fail(&mut conversation), Next::Retry);
+        assert_eq!(fail(&mut conversation), Next::LockOut(limits.lockout));
+        assert!(conversation.is_locked_out());
+        assert!(!conversation.can_switch_identity());
+        assert_eq!(fail(&mut conversation), Next::Retry);
+        assert_eq!(fail(&mut conversation), Next::GiveUp);
+    }
+
+    #[test]
+    fn recoverable_failures_are_not_counted() {
+        let now = Instant::now();
+        let mut conversation = Conversation::new(now);
+        conversation.message(now, true);
+        answered(&mut conversation, now);
+        assert_eq!(
+            conversation.completed(false, ONE_TRY, now),
+            Completion::Failed {
+                counted: false,
+                next: Next::Retry
+            }
+        );
+        // Only the attempt it happened in is excused.
+        conversation.attempt_started(now);
+        answered(&mut conversation, now);
+        assert_eq!(
+            conversation.completed(false, ONE_TRY, now),
+            Completion::Failed {
+                counted: true,
+                next: Next::GiveUp
+            }
+        );
+    }
+
+    #[test]
+    fn a_failure_retires_the_attempt() {
+        let now = Instant::now();
+        let mut conversation = Conversation::new(now);
+        answered(&mut conversation, now);
+        conversation.completed(
+            false,
+            Limits {
+                max_attempts: 0,
+                lockout: Duration::ZERO,
+            },
+            now,
+        );
+        assert_eq!(conversation.attempt_id(), 2);
+    }
+
+    #[test]
+    fn silence_is_measured_from_the_last_activity() {
+        let start = Instant::now();
+        let mut conversation = Conversation::new(start);
+        let later = start + Duration::from_secs(5);
+        assert_eq!(conversation.silent_for(later), Duration::from_secs(5));
+        conversation.message(later, false);
+        assert_eq!(conversation.silent_for(later), Duration::ZERO);
+    }
+
+    /// Stands in for polkit-agent-helper-1: plays a script of the lines it
+    /// writes (`PAM_PROMPT_ECHO_OFF`, `PAM_TEXT_INFO`, `SUCCESS`, ...) into a
+    /// conversation the way the listener relays libpolkit-agent's signals.
+    /// `> answer` lines are the user answering the latest prompt, `@switch`
+    /// and `@cancel` the user switching identity or cancelling. A `[n]`
+    /// prefix makes a line come from attempt `n` rather than the live one.
+    fn play(script: &[&str], limits: Limits) -> Vec<String> {
+        let now = Instant::now();
+        let mut conversation = Some(Conversation::new(now));
+        let mut prompt_id = 0;
+        let mut log = Vec::new();
+        for line in script {
+            let Some(live) = conversation.as_mut() else {
+                log.push(format!("ignored {line}"));
+                continue;
+            };
+            let (attempt, line) = match line.strip_prefix('[') {
+                Some(rest) => {
+                    let (attempt, line) = rest.split_once("] ").unwrap();
+                    (attempt.parse().unwrap(), line)
+                }
+                None => (live.attempt_id(), *line),
+            };
+            if attempt != live.attempt_id() {
+                log.push(format!("ignored {line}"));
+                continue;
+            }
+
+            let (kind, text) = line.split_once(' ').unwrap_or((line, ""));
+            match kind {
+                "PAM_PROMPT_ECHO_OFF" | "PAM_PROMPT_ECHO_ON" => {
+                    prompt_id = live.prompt(now);
+                    log.push(format!("prompt {prompt_id}: {text}"));
+                }
+                "PAM_TEXT_INFO" => {
+                    live.message(now, false);
+                    log.push(format!("info: {text}"));
+                }
+                "PAM_ERROR_MSG" => {
+                    live.message(now, crate::pam::AuthMethod::is_recoverable(text));
+                    log.push(format!("error: {text}"));
+                }
+                ">" => log.push(format!("answer accepted: {}", live.respond(prompt_id, now))),
+                "SUCCESS" | "FAILURE" => {
+                    let completion = live.completed(kind == "SUCCESS", limits, now);
+                    log.push(format!("{completion:?}"));
+                    match completion {
+                        Completion::Failed {
+                            next: Next::Retry, ..
+                        } => live.attempt_started(now),
+                        Completion::Failed {
+                            next: Next::LockOut(_),
+                            ..
+                        } => {}
+                        _ => conversation = None,
+                    }
+                }
+                "@switch" if live.can_switch_identity() => {
+                    live.retire_attempt();
+                    live.attempt_started(now);
+                    log.push(format!("switched to attempt {}", live.attempt_id()));
+                }
+                "@switch" => log.push("switch refused".to_owned()),
+                "@cancel" => {
+                    conversation = None;
+                    log.push("cancelled".to_owned());
+                }
+                _ => panic!("unknown script line {line}"),
+            }
+        }
+        log
+    }
+
+    #[test]
+    fn scripted_password_success() {
+        let log = play(
+            &[
+                "PAM_TEXT_INFO Last login failed 2 times",
+                "PAM_PROMPT_ECHO_OFF Password: ",
+                "> hunter2",
+                "SUCCESS",
+                "SUCCESS",
+            ],
+            ONE_TRY,
+        );
+        assert_eq!(
+            log,
+            [
+                "info: Last login failed 2 times",
+                "prompt 1: Password: ",
+                "answer accepted: true",
+                "Succeeded",
+                "ignored SUCCESS",
+            ]
+        );
+    }
+
+    #[test]
+    fn scripted_failure_then_retry() {
+        let log = play(
+            &[
+                "PAM_PROMPT_ECHO_OFF Password: ",
+                "> wrong",
+                "PAM_ERROR_MSG Authentication failure",
+                "FAILURE",
+                "[1] SUCCESS",
+                "PAM_PROMPT_ECHO_OFF Password: ",
+                "> right",
+                "SUCCESS",
+            ],
+            Limits {
+                max_attempts: 3,
+                lockout: Duration::ZERO,
+            },
+        );
+        assert_eq!(
+            log,
+            [
+                "prompt 1: Password: ",
+                "answer accepted: true",
+                "error: Authentication failure",
+                "Failed { counted: true, next: Retry }",
+                "ignored SUCCESS",
+                "prompt 2: Password: ",
+                "answer accepted: true",
+                "Succeeded",
+            ]
+        );
+    }
+
+    #[test]
+    fn scripted_failure_with_one_try() {
+        let log = play(
+            &["PAM_PROMPT_ECHO_OFF Password: ", "> wrong", "FAILURE"],
+            ONE_TRY,
+        );
+        assert_eq!(log[2], "Failed { counted: true, next: GiveUp }");
+    }
+
+    #[test]
+    fn scripted_helper_that_never_ran() {
+        assert_eq!(play(&["FAILURE"], ONE_TRY), ["NeverRan"]);
+    }
+
+    #[test]
+    fn scripted_pulled_smartcard_is_not_counted() {
+        let log = play(
+            &[
+                "PAM_PROMPT_ECHO_OFF PIN for Jose's card: ",
+                "> 1234",
+                "PAM_ERROR_MSG Smart card removed",
+                "FAILURE",
+            ],
+            ONE_TRY,
+        );
+        assert_eq!(log[3], "Failed { counted: false, next: Retry }");
+    }
+
+    #[test]
+    fn scripted_cancel() {
+        let log = play(
+            &["PAM_PROMPT_ECHO_OFF Password: ", "@cancel", "SUCCESS"],
+            ONE_TRY,
+        );
+        assert_eq!(
+            log,
+            ["prompt 1: Password: ", "cancelled", "ignored SUCCESS"]
+        );
+    }
+
+    #[test]
+    fn scripted_user_change() {
+        let log = play(
+            &[
+                "PAM_TEXT_INFO Place your finger on the fingerprint reader",
+                "@switch",
+                "[1] PAM_PROMPT_ECHO_OFF Password: ",
+                "PAM_PROMPT_ECHO_OFF Password: ",
+                "> hunter2",
+                "@switch",
+                "SUCCESS",
+            ],
+            ONE_TRY,
+        );
+        assert_eq!(
+            log,
+            [
+                "info: Place your finger on the fingerprint reader",
+                "switched to attempt 2",
+                "ignored PAM_PROMPT_ECHO_OFF Password: ",
+                "prompt 1: Password: ",
+                "answer accepted: true",
+                "switch refused",
+                "Succeeded",
+            ]
+        );
+    }
+}
@@ -0,0 +1,64 @@
+//! Information about the process that triggered an authentication request.
+
+use std::collections::BTreeMap;
+use std::fs;
+
+/// Detail key polkitd sets to the pid of the process asking for authorization.
+const CALLER_PID_KEY: &str = "polkit.caller-pid";
+
+/// Detail key polkitd sets to the pid of the subject, the process the
+/// authorization is for.
+const SUBJECT_PID_KEY: &str = "polkit.subject-pid";
+
+/// Pid of the requesting process, if polkitd reported it.
+pub fn caller_pid(details: &BTreeMap<String, String>) -> Option<u32> {
+    details.get(CALLER_PID_KEY)?.trim().parse().ok()
+}
+
+/// Pid of the process whose authorization is being checked. For pkexec this
+/// is the program that ran it, where the caller is pkexec itself.
+pub fn subject_pid(details: &BTreeMap<String, String>) -> Option<u32> {
+    details.get(SUBJECT_PID_KEY)?.trim().parse().ok()
+}
+
+/// Short program name (`/proc/<pid>/comm`) of a running process.
+pub fn program_name(pid: u32) -> Option<String> {
+    let comm = fs::read_to_string(format!("/proc/{pid}/comm")).ok()?;
+    let comm = comm.trim_end();
+    (!comm.is_empty()).then(|| comm.to_owned())
+}
+
+/// Longest command line shown before it is cut off with an ellipsis.
+const MAX_COMMAND_LINE: usize = 120;
+
+/// Full command line of a running process, arguments separated by spaces.
+pub fn command_line(pid: u32) -> Option<String> {
+    let raw = fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    let args: Vec<String> = raw
+        .split(|byte| *byte == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    (!args.is_empty()).then(|| args.join(" "))
+}
+
+/// Path of a running process's executable. Unreadable for processes of
+/// other users, so this is often `None` for root callers.
+pub fn executable(pid: u32) -> Option<String> {
+    let path = fs::read_link(format!("/proc/{pid}/exe")).ok()?;
+    Some(path.to_string_lossy().into_owned())
+}
+
+/// "Requested by" text for the dialog: command line (or program name), the
+/// pid and, when it isn't obvious from the command, the executable. `None`
+/// if the process is already gone.
+pub fn describe(pid: u32) -> Option<String> {
+    let mut command = command_line(pid).or_else(|| program_name(pid))?;
+    if command.chars().count() > MAX_COMMAND_LINE {
+        command = command.chars().take(MAX_COMMAND_LINE).collect::<String>() + "…";
+    }
+    match executable(pid) {
+        Some(exe) if !command.starts_with(&exe) => Some(format!("{command} (pid {pid}, {exe})")),
+        _ => Some(format!("{command} (pid {pid})")),
+    }
+}
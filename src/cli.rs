@@ -0,0 +1,200 @@
+//! Command-line argument parsing.
+
+use std::path::PathBuf;
+
+const USAGE: &str = "\
+Usage: badged [OPTIONS]
+       badged status [--actions]
+       badged doctor
+       badged report [--output <DIR>] [--log-file <PATH>]
+
+Commands:
+  status             Show recorded prompt statistics
+    --actions        Break statistics down per polkit action
+  doctor             Check the environment badged depends on
+  report             Bundle diagnostics into a tarball for bug reports
+    --output <DIR>   Where to write it (default: current directory)
+    --log-file <PATH>
+                     The log file badged writes to (default:
+                     ~/.local/state/badged/badged.log)
+
+Options:
+  --log-file <PATH>  Also write logs to PATH (rotated by size)
+  --json-events      Print authentication lifecycle events as JSON lines on stdout
+  --replace          Take over from a running badged instance
+  --fallback         Register as a fallback agent, used only when no other
+                     agent is registered for the session
+  -h, --help         Print this help and exit
+  -V, --version      Print version and exit";
+
+/// Options given on the command line.
+#[derive(Debug, Default)]
+pub struct Options {
+    pub log_file: Option<PathBuf>,
+    pub json_events: bool,
+    pub replace: bool,
+    pub fallback: bool,
+}
+
+/// What `main` should do after parsing arguments.
+pub enum Command {
+    Run(Options),
+    Status {
+        actions: bool,
+    },
+    Doctor,
+    Report {
+        output: PathBuf,
+        log_file: Option<PathBuf>,
+    },
+    Help,
+    Version,
+}
+
+impl Command {
+    pub fn from_env() -> Result<Self, String> {
+        Self::parse(std::env::args().skip(1))
+    }
+
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut args = args.peekable();
+
+        match args.peek().map(String::as_str) {
+            Some("status") => {
+                args.next();
+                let mut actions = false;
+                for arg in args {
+                    match arg.as_str() {
+                        "--actions" => actions = true,
+                        _ => return Err(format!("unexpected argument '{arg}' for status")),
+                    }
+                }
+                return Ok(Command::Status { actions });
+            }
+            Some("doctor") => {
+                args.next();
+                if let Some(arg) = args.next() {
+                    return Err(format!("unexpected argument '{arg}' for doctor"));
+                }
+                return Ok(Command::Doctor);
+            }
+            Some("report") => {
+                args.next();
+                let mut output = PathBuf::from(".");
+                let mut log_file = None;
+                while let Some(arg) = args.next() {
+                    match arg.as_str() {
+                        "--output" => {
+                            output = PathBuf::from(args.next().ok_or("--output requires a path")?);
+                        }
+                        "--log-file" => {
+                            let path = args.next().ok_or("--log-file requires a path")?;
+                            log_file = Some(PathBuf::from(path));
+                        }
+                        _ => return Err(format!("unexpected argument '{arg}' for report")),
+                    }
+                }
+                return Ok(Command::Report { output, log_file });
+            }
+            _ => {}
+        }
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help),
+                "-V" | "--version" => return Ok(Command::Version),
+                "--json-events" => options.json_events = true,
+                "--replace" => options.replace = true,
+                "--fallback" => options.fallback = true,
+                "--log-file" => {
+                    let path = args.next().ok_or("--log-file requires a path")?;
+                    options.log_file = Some(PathBuf::from(path));
+                }
+                _ => {
+                    if let Some(path) = arg.strip_prefix("--log-file=") {
+                        options.log_file = Some(PathBuf::from(path));
+                    } else {
+                        return Err(format!("unexpected argument '{arg}'"));
+                    }
+                }
+            }
+        }
+
+        Ok(Command::Run(options))
+    }
+}
+
+pub fn usage() -> &'static str {
+    USAGE
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::Command;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        Command::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_run_options() {
+        let Ok(Command::Run(options)) = parse(&["--json-events", "--log-file", "/tmp/b.log"])
+        else {
+            panic!("expected Run");
+        };
+        assert!(options.json_events);
+        assert!(!options.replace && !options.fallback);
+        assert_eq!(options.log_file.as_deref(), Some(Path::new("/tmp/b.log")));
+
+        let Ok(Command::Run(options)) = parse(&["--log-file=x.log", "--replace", "--fallback"])
+        else {
+            panic!("expected Run");
+        };
+        assert_eq!(options.log_file, Some(PathBuf::from("x.log")));
+        assert!(options.replace && options.fallback);
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        assert!(matches!(
+            parse(&["status"]),
+            Ok(Command::Status { actions: false })
+        ));
+        assert!(matches!(
+            parse(&["status", "--actions"]),
+            Ok(Command::Status { actions: true })
+        ));
+        assert!(matches!(parse(&["doctor"]), Ok(Command::Doctor)));
+        assert!(matches!(parse(&["--help"]), Ok(Command::Help)));
+        assert!(matches!(parse(&["-V"]), Ok(Command::Version)));
+    }
+
+    #[test]
+    fn parses_report_options() {
+        let Ok(Command::Report { output, log_file }) = parse(&["report"]) else {
+            panic!("expected Report");
+        };
+        assert_eq!(output, PathBuf::from("."));
+        assert_eq!(log_file, None);
+
+        let Ok(Command::Report { output, log_file }) =
+            parse(&["report", "--output", "out", "--log-file", "b.log"])
+        else {
+            panic!("expected Report");
+        };
+        assert_eq!(output, PathBuf::from("out"));
+        assert_eq!(log_file, Some(PathBuf::from("b.log")));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(&["--bogus"]).is_err());
+        assert!(parse(&["--log-file"]).is_err());
+        assert!(parse(&["status", "--bogus"]).is_err());
+        assert!(parse(&["doctor", "extra"]).is_err());
+        assert!(parse(&["report", "--output"]).is_err());
+    }
+}
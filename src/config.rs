@@ -0,0 +1,274 @@
+//! User configuration, read from `$XDG_CONFIG_HOME/badged/config.toml`.
+//!
+//! Every key is optional; a missing file means defaults. Keys that don't
+//! parse are dropped one by one so the rest still apply, and when the `deny`
+//! list can't be read every action is denied rather than none.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use gtk4::gio;
+use serde::Deserialize;
+
+use crate::prompt::PromptConfig;
+use crate::theme::Theme;
+
+const DEFAULT_APP_ID: &str = "dev.badged.Badged";
+const DEFAULT_BUS_NAME: &str = "dev.badged.Agent";
+const DEFAULT_HELPER_TIMEOUT: u64 = 60;
+const DEFAULT_HELPER_DEADLINE: u64 = 300;
+const DEFAULT_MAX_ATTEMPTS: u32 = 1;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// GApplication id used by the dialog.
+    pub app_id: String,
+    /// Well-known name claimed on the session bus.
+    pub bus_name: String,
+    /// External program that collects the secret instead of the dialog.
+    pub prompt: Option<PromptConfig>,
+    /// Replacement prompt messages keyed by action id (a trailing `*` matches
+    /// any suffix). `{message}`, `{program}`, `{user}` and `{device}` are
+    /// substituted.
+    pub messages: BTreeMap<String, String>,
+    /// Seconds the authentication helper may stay silent, while not waiting
+    /// for input, before the dialog offers to restart it. 0 disables.
+    pub helper_timeout: u64,
+    /// Seconds of helper silence, while not waiting for input, after which
+    /// it is killed and the request fails. 0 disables.
+    pub helper_deadline: u64,
+    /// Seconds a prompt may wait for an answer before the request is
+    /// cancelled. 0 waits indefinitely.
+    pub prompt_timeout: u64,
+    /// Identity to preselect when polkit offers it; defaults to the user
+    /// running badged.
+    pub default_user: Option<String>,
+    /// Requests accepted per minute from one process, identified by the
+    /// subject's pid and executable; later ones are rejected without a
+    /// dialog. 0 (the default) disables.
+    pub rate_limit: u32,
+    /// Action ids (a trailing `*` matches any suffix) rejected immediately,
+    /// without a dialog.
+    pub deny: Vec<String>,
+    /// Failed attempts allowed per request before locking out (or failing
+    /// it). The default, 1, fails the request on the first failure; higher
+    /// values keep the dialog open for another try. 0 allows unlimited
+    /// attempts.
+    pub max_attempts: u32,
+    /// Seconds input stays disabled once `max_attempts` is reached, before
+    /// one more round of attempts. 0 fails the request right away.
+    pub lockout_seconds: u64,
+    /// Show an on-screen numeric keypad when PAM asks for a PIN.
+    pub pin_pad: bool,
+    /// Built-in style preset layered over the dialog's CSS.
+    pub theme: Theme,
+    /// Write each request's PAM conversation (never the responses) to
+    /// `$XDG_STATE_HOME/badged/transcripts/`.
+    pub record_transcripts: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            app_id: DEFAULT_APP_ID.to_owned(),
+            bus_name: DEFAULT_BUS_NAME.to_owned(),
+            prompt: None,
+            messages: BTreeMap::new(),
+            helper_timeout: DEFAULT_HELPER_TIMEOUT,
+            helper_deadline: DEFAULT_HELPER_DEADLINE,
+            prompt_timeout: 0,
+            default_user: None,
+            rate_limit: 0,
+            deny: Vec::new(),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            lockout_seconds: 0,
+            pin_pad: false,
+            theme: Theme::default(),
+            record_transcripts: false,
+        }
+    }
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("badged").join("config.toml"))
+}
+
+/// `$XDG_STATE_HOME/badged`, for counters, logs and reports.
+pub fn state_dir() -> Option<PathBuf> {
+    let state_dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(state_dir.join("badged"))
+}
+
+impl Config {
+    /// Load the config file. See the module docs for what happens on errors.
+    pub fn load() -> Self {
+        let Some(path) = config_path() else {
+            return Self::default();
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Self::default(),
+            Err(err) => {
+                log!("[config] Cannot read {}: {err}", path.display());
+                return Self::deny_all();
+            }
+        };
+
+        let mut config = Self::parse(&contents, &path);
+        config.validate();
+        config
+    }
+
+    /// Parse `contents`, keeping every key that is valid on its own.
+    fn parse(contents: &str, path: &Path) -> Self {
+        let table: toml::Table = match contents.parse() {
+            Ok(table) => table,
+            Err(err) => {
+                log!("[config] Cannot parse {}: {err}", path.display());
+                return Self::deny_all();
+            }
+        };
+
+        let mut valid = toml::Table::new();
+        for (key, value) in table {
+            let single = toml::Table::from_iter([(key.clone(), value.clone())]);
+            match toml::Value::Table(single).try_into::<Self>() {
+                Ok(_) => {
+                    valid.insert(key, value);
+                }
+                Err(err) if key == "deny" => {
+                    log!("[config] Invalid deny in {}: {err}", path.display());
+                    return Self::deny_all();
+                }
+                Err(err) => log!("[config] Ignoring {key} in {}: {err}", path.display()),
+            }
+        }
+        toml::Value::Table(valid).try_into().unwrap_or_else(|err| {
+            log!("[config] Ignoring invalid {}: {err}", path.display());
+            Self::deny_all()
+        })
+    }
+
+    /// Defaults, except that every action is denied: what a config whose
+    /// `deny` list can't be read falls back to, so it fails closed.
+    fn deny_all() -> Self {
+        log!("[config] Denying every action until the config file is fixed");
+        Self {
+            deny: vec!["*".to_owned()],
+            ..Self::default()
+        }
+    }
+
+    fn validate(&mut self) {
+        if !gio::Application::id_is_valid(&self.app_id) {
+            log!(
+                "[config] Invalid app_id '{}', using {DEFAULT_APP_ID}",
+                self.app_id
+            );
+            self.app_id = DEFAULT_APP_ID.to_owned();
+        }
+        if !gio::dbus_is_name(&self.bus_name) || gio::dbus_is_unique_name(&self.bus_name) {
+            log!(
+                "[config] Invalid bus_name '{}', using {DEFAULT_BUS_NAME}",
+                self.bus_name
+            );
+            self.bus_name = DEFAULT_BUS_NAME.to_owned();
+        }
+    }
+}
+
+/// Whether `pattern` (an exact action id, or a prefix ending in `*`) matches.
+pub fn action_matches(pattern: &str, action_id: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => action_id.starts_with(prefix),
+        None => pattern == action_id,
+    }
+}
+
+impl Config {
+    /// Whether a `deny` pattern matches `action_id`.
+    pub fn is_denied(&self, action_id: &str) -> bool {
+        self.deny
+            .iter()
+            .any(|pattern| action_matches(pattern, action_id))
+    }
+
+    /// The message template configured for `action_id`. Exact ids win over
+    /// wildcards, and longer wildcards over shorter ones.
+    pub fn message_template(&self, action_id: &str) -> Option<&str> {
+        if let Some(template) = self.messages.get(action_id) {
+            return Some(template);
+        }
+        self.messages
+            .iter()
+            .filter(|(pattern, _)| pattern.ends_with('*') && action_matches(pattern, action_id))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, template)| template.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{action_matches, Config};
+
+    #[test]
+    fn matches_exact_ids_and_prefixes() {
+        assert!(action_matches("org.gnome.foo", "org.gnome.foo"));
+        assert!(!action_matches("org.gnome.foo", "org.gnome.foobar"));
+        assert!(action_matches("org.gnome.*", "org.gnome.foo"));
+        assert!(action_matches("*", "anything"));
+        assert!(!action_matches("org.gnome.*", "org.freedesktop.foo"));
+        // Only a trailing `*` is a wildcard.
+        assert!(!action_matches("org.*.foo", "org.gnome.foo"));
+    }
+
+    #[test]
+    fn picks_the_most_specific_template() {
+        let mut config = Config::default();
+        for (pattern, template) in [
+            ("org.*", "short"),
+            ("org.gnome.*", "long"),
+            ("org.gnome.foo", "exact"),
+        ] {
+            config
+                .messages
+                .insert(pattern.to_owned(), template.to_owned());
+        }
+        assert_eq!(config.message_template("org.gnome.foo"), Some("exact"));
+        assert_eq!(config.message_template("org.gnome.bar"), Some("long"));
+        assert_eq!(config.message_template("org.kde.bar"), Some("short"));
+        assert_eq!(config.message_template("com.example"), None);
+    }
+
+    #[test]
+    fn keeps_valid_keys_next_to_a_bad_one() {
+        let config = Config::parse(
+            "deny = [\"org.example.*\"]\npin_pad = true\nrate_limt = 5\nmax_attempts = \"three\"\n",
+            Path::new("config.toml"),
+        );
+        assert_eq!(config.deny, ["org.example.*"]);
+        assert!(config.pin_pad);
+        assert_eq!(config.max_attempts, 1);
+        assert!(config.is_denied("org.example.reboot"));
+        assert!(!config.is_denied("org.gnome.foo"));
+    }
+
+    #[test]
+    fn unreadable_deny_list_denies_everything() {
+        let bad_deny = Config::parse("deny = \"org.example.*\"\n", Path::new("config.toml"));
+        assert!(bad_deny.is_denied("org.gnome.foo"));
+        let bad_syntax = Config::parse("deny = [\"org.example.*\"\n", Path::new("config.toml"));
+        assert!(bad_syntax.is_denied("org.gnome.foo"));
+    }
+}
@@ -0,0 +1,260 @@
+//! Control interface on our session bus name.
+//!
+//! Exports `dev.badged.Agent1` at `/dev/badged/Agent` and emits lifecycle
+//! signals so other desktop components can react to prompts without polling.
+//! Scripts can also query the agent's state, reload its config, switch the
+//! dialog's theme or stop it.
+
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+
+use gtk4::gio;
+use gtk4::glib;
+use gtk4::prelude::*;
+
+use crate::listener::SharedState;
+use crate::theme::Theme;
+
+const OBJECT_PATH: &str = "/dev/badged/Agent";
+const INTERFACE_NAME: &str = "dev.badged.Agent1";
+
+const INTROSPECTION_XML: &str = r#"
+<node>
+  <interface name="dev.badged.Agent1">
+    <method name="Status">
+      <arg name="registered" type="b" direction="out"/>
+      <arg name="pending" type="u" direction="out"/>
+    </method>
+    <method name="PendingRequests">
+      <arg name="requests" type="a(ts)" direction="out"/>
+    </method>
+    <method name="Reload"/>
+    <method name="SetTheme">
+      <arg name="theme" type="s" direction="in"/>
+    </method>
+    <method name="Quit"/>
+    <signal name="AuthenticationStarted">
+      <arg name="action_id" type="s"/>
+      <arg name="user" type="s"/>
+    </signal>
+    <signal name="AuthenticationFinished">
+      <arg name="action_id" type="s"/>
+      <arg name="user" type="s"/>
+      <arg name="success" type="b"/>
+    </signal>
+    <signal name="AuthenticationCancelled">
+      <arg name="action_id" type="s"/>
+      <arg name="user" type="s"/>
+    </signal>
+  </interface>
+</node>
+"#;
+
+#[derive(Default)]
+pub struct Control {
+    connection: RefCell<Option<gio::DBusConnection>>,
+    shared: RefCell<Weak<SharedState>>,
+    registered: Cell<bool>,
+}
+
+impl Control {
+    pub fn new() -> Rc<Self> {
+        Rc::new(Self::default())
+    }
+
+    /// Answer method calls from the request state in `shared`.
+    pub fn attach(&self, shared: &Rc<SharedState>) {
+        *self.shared.borrow_mut() = Rc::downgrade(shared);
+    }
+
+    /// Whether the polkit listener is currently registered, for `Status`.
+    pub fn set_registered(&self, registered: bool) {
+        self.registered.set(registered);
+    }
+
+    /// Claim `bus_name` on the session bus and export the control object,
+    /// waiting until the bus has answered. The name is held until the
+    /// returned id is passed to `gio::bus_unown_name`.
+    ///
+    /// The request is never queued: if another badged holds the name this
+    /// fails, unless `replace` takes it over. Losing the name later means a
+    /// new instance took over, so this one quits.
+    pub fn own_name(
+        self: &Rc<Self>,
+        bus_name: &str,
+        replace: bool,
+    ) -> Result<gio::OwnerId, String> {
+        let mut flags =
+            gio::BusNameOwnerFlags::DO_NOT_QUEUE | gio::BusNameOwnerFlags::ALLOW_REPLACEMENT;
+        if replace {
+            flags |= gio::BusNameOwnerFlags::REPLACE;
+        }
+
+        // `None` until the bus answers, then whether the name is ours.
+        let owned = Rc::new(Cell::new(None));
+        let owned_acquired = owned.clone();
+        let owned_lost = owned.clone();
+        let weak = Rc::downgrade(self);
+        let weak_lost = Rc::downgrade(self);
+        let id = gio::bus_own_name(
+            gio::BusType::Session,
+            bus_name,
+            flags,
+            move |connection, _| {
+                if let Some(control) = weak.upgrade() {
+                    control.export(connection);
+                }
+            },
+            move |_, name| {
+                log!("[control] Acquired bus name {name}");
+                owned_acquired.set(Some(true));
+            },
+            move |_, name| {
+                if let Some(control) = weak_lost.upgrade() {
+                    control.connection.borrow_mut().take();
+                }
+                if owned_lost.replace(Some(false)) == Some(true) {
+                    log!("[control] Bus name {name} was taken over by a new badged instance; quitting");
+                    if let Some(app) = gio::Application::default() {
+                        app.quit();
+                    }
+                }
+            },
+        );
+
+        let context = glib::MainContext::default();
+        while owned.get().is_none() {
+            context.iteration(true);
+        }
+        if owned.get() == Some(true) {
+            return Ok(id);
+        }
+        gio::bus_unown_name(id);
+        Err(if replace {
+            format!("Could not take over the bus name {bus_name} from the running badged.")
+        } else {
+            format!(
+                "Another badged instance already owns the bus name {bus_name}. \
+                 Stop it first or run `badged --replace` to take over."
+            )
+        })
+    }
+
+    fn export(self: &Rc<Self>, connection: gio::DBusConnection) {
+        let interface = gio::DBusNodeInfo::for_xml(INTROSPECTION_XML)
+            .ok()
+            .and_then(|node| node.lookup_interface(INTERFACE_NAME))
+            .expect("Control interface XML is valid");
+
+        let weak = Rc::downgrade(self);
+        let registration = connection
+            .register_object(OBJECT_PATH, &interface)
+            .method_call(
+                move |_, sender, _, _, method, _, invocation| match weak.upgrade() {
+                    Some(control) => control.handle_method(sender, method, invocation),
+                    None => invocation.return_dbus_error(
+                        "org.freedesktop.DBus.Error.Failed",
+                        "badged is shutting down",
+                    ),
+                },
+            )
+            .build();
+        match registration {
+            Ok(_) => *self.connection.borrow_mut() = Some(connection),
+            Err(err) => log!("[control] Failed to export {OBJECT_PATH}: {err}"),
+        }
+    }
+
+    fn handle_method(
+        &self,
+        sender: Option<&str>,
+        method: &str,
+        invocation: gio::DBusMethodInvocation,
+    ) {
+        let shared = self.shared.borrow().upgrade();
+        let pending = shared
+            .as_ref()
+            .map(|shared| shared.pending_requests())
+            .unwrap_or_default();
+
+        match method {
+            "Status" => {
+                let reply = (self.registered.get(), pending.len() as u32).to_variant();
+                invocation.return_value(Some(&reply));
+            }
+            "PendingRequests" => {
+                invocation.return_value(Some(&(pending,).to_variant()));
+            }
+            "Reload" => {
+                if let Some(shared) = shared {
+                    shared.reload_config();
+                }
+                invocation.return_value(None);
+            }
+            "SetTheme" => {
+                let name = invocation
+                    .parameters()
+                    .get::<(String,)>()
+                    .map(|(name,)| name)
+                    .unwrap_or_default();
+                let Some(theme) = Theme::from_name(&name) else {
+                    let known: Vec<&str> = Theme::ALL.iter().map(|theme| theme.name()).collect();
+                    invocation.return_dbus_error(
+                        "org.freedesktop.DBus.Error.InvalidArgs",
+                        &format!(
+                            "Unknown theme {name:?}; expected one of {}",
+                            known.join(", ")
+                        ),
+                    );
+                    return;
+                };
+                if let Some(shared) = shared {
+                    shared.set_theme(theme);
+                }
+                invocation.return_value(None);
+            }
+            "Quit" => {
+                log!(
+                    "[control] Quit requested by {}",
+                    sender.unwrap_or("unknown")
+                );
+                invocation.return_value(None);
+                if let Some(app) = gio::Application::default() {
+                    app.quit();
+                }
+            }
+            _ => invocation.return_dbus_error(
+                "org.freedesktop.DBus.Error.UnknownMethod",
+                &format!("Unknown method {method}"),
+            ),
+        }
+    }
+
+    pub fn authentication_started(&self, action_id: &str, user: &str) {
+        self.emit("AuthenticationStarted", (action_id, user).to_variant());
+    }
+
+    pub fn authentication_finished(&self, action_id: &str, user: &str, success: bool) {
+        self.emit(
+            "AuthenticationFinished",
+            (action_id, user, success).to_variant(),
+        );
+    }
+
+    /// Sent before `AuthenticationFinished` when a request ended without an
+    /// answer: dismissed, cancelled by polkitd, or abandoned on shutdown.
+    pub fn authentication_cancelled(&self, action_id: &str, user: &str) {
+        self.emit("AuthenticationCancelled", (action_id, user).to_variant());
+    }
+
+    fn emit(&self, signal: &str, parameters: glib::Variant) {
+        let Some(connection) = self.connection.borrow().clone() else {
+            return;
+        };
+        if let Err(err) =
+            connection.emit_signal(None, OBJECT_PATH, INTERFACE_NAME, signal, Some(&parameters))
+        {
+            log!("[control] Failed to emit {signal}: {err}");
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Recognized polkit request details, labelled for display.
+//!
+//! Mechanisms attach free-form key/value details to a request (systemd passes
+//! the unit and verb, udisks the device, pkexec the command line). Keys
+//! listed here get a readable label and come first; other keys follow as-is,
+//! except polkit's internal `polkit.*` ones. The table opens with the action
+//! id and closes with the action's vendor, from its policy file.
+
+use std::cell::{OnceCell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+
+use polkit_agent_rs::gio;
+use polkit_agent_rs::polkit;
+
+/// Detail keys worth showing, in display order, with their labels.
+const KNOWN_DETAILS: &[(&str, &str)] = &[
+    ("command_line", "Command"),
+    ("program", "Program"),
+    ("user", "Run as"),
+    ("unit", "Unit"),
+    ("verb", "Operation"),
+    ("device", "Device"),
+    ("drive", "Drive"),
+    ("drive.name", "Drive"),
+    ("drive.vendor", "Drive vendor"),
+    ("drive.model", "Model"),
+    ("id_label", "Label"),
+    ("fstype", "Filesystem"),
+    ("role", "Software task"),
+];
+
+/// The action id, the entries of `details` worth showing and the action's
+/// vendor, as `(label, value)` rows.
+pub fn rows(action_id: &str, details: &BTreeMap<String, String>) -> Vec<(String, String)> {
+    let known = KNOWN_DETAILS.iter().filter_map(|(key, label)| {
+        let value = details.get(*key)?.trim();
+        (!value.is_empty()).then(|| ((*label).to_owned(), value.to_owned()))
+    });
+    let extra = details
+        .iter()
+        .filter(|(key, value)| {
+            !key.starts_with("polkit.")
+                && !value.trim().is_empty()
+                && !KNOWN_DETAILS.iter().any(|(known, _)| known == key)
+        })
+        .map(|(key, value)| (key.clone(), value.trim().to_owned()));
+
+    std::iter::once(("Action".to_owned(), action_id.to_owned()))
+        .chain(known)
+        .chain(extra)
+        .chain(vendor(action_id))
+        .collect()
+}
+
+/// Vendor name and URL per action id. Filled from polkit's action list on
+/// first use and dropped whenever polkitd reports that its actions changed.
+type Vendors = HashMap<String, (String, String)>;
+
+thread_local! {
+    static VENDORS: RefCell<Option<Vendors>> = const { RefCell::new(None) };
+    /// Kept alive so its `changed` handler stays connected.
+    static AUTHORITY: OnceCell<polkit::Authority> = const { OnceCell::new() };
+}
+
+/// Vendor name and URL from the action's policy file.
+fn vendor(action_id: &str) -> Vec<(String, String)> {
+    let cached = VENDORS.with_borrow(|vendors| {
+        vendors
+            .as_ref()
+            .map(|vendors| vendors.get(action_id).cloned())
+    });
+    let vendor = match cached {
+        Some(vendor) => vendor,
+        None => load_vendors().and_then(|vendors| {
+            let vendor = vendors.get(action_id).cloned();
+            VENDORS.set(Some(vendors));
+            vendor
+        }),
+    };
+    let Some((name, url)) = vendor else {
+        return Vec::new();
+    };
+
+    [("Vendor", name), ("Website", url)]
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(label, value)| (label.to_owned(), value))
+        .collect()
+}
+
+fn load_vendors() -> Option<Vendors> {
+    let authority = AUTHORITY.with(|authority| {
+        authority
+            .get_or_init(|| {
+                let authority = polkit::Authority::get();
+                authority.connect_changed(|_| VENDORS.set(None));
+                authority
+            })
+            .clone()
+    });
+    let actions = match authority.enumerate_actions_sync(None::<&gio::Cancellable>) {
+        Ok(actions) => actions,
+        Err(err) => {
+            log!("[details] Cannot list polkit actions: {err}");
+            return None;
+        }
+    };
+    Some(
+        actions
+            .into_iter()
+            .map(|action| {
+                let vendor = (
+                    action.vendor_name().to_string(),
+                    action.vendor_url().to_string(),
+                );
+                (action.action_id().to_string(), vendor)
+            })
+            .collect(),
+    )
+}
+
+/// Expand `$(key)` placeholders in a polkit message from `details`.
+///
+/// polkitd normally does this before the message reaches us; this catches
+/// placeholders it left behind. Unknown keys are kept verbatim.
+pub fn expand_placeholders(message: &str, details: &BTreeMap<String, String>) -> String {
+    let mut out = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(start) = rest.find("$(") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find(')') {
+            Some(end) => {
+                let key = &after[..end];
+                match details.get(key) {
+                    Some(value) => out.push_str(value),
+                    None => out.push_str(&rest[start..start + 2 + end + 1]),
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::expand_placeholders;
+
+    fn details(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn expands_known_keys() {
+        let details = details(&[("unit", "sshd.service"), ("verb", "restart")]);
+        assert_eq!(
+            expand_placeholders("Authentication is required to $(verb) '$(unit)'.", &details),
+            "Authentication is required to restart 'sshd.service'."
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_keys() {
+        let details = details(&[("unit", "sshd.service")]);
+        assert_eq!(
+            expand_placeholders("$(verb) $(unit)", &details),
+            "$(verb) sshd.service"
+        );
+    }
+
+    #[test]
+    fn keeps_unterminated_placeholders() {
+        let details = details(&[("unit", "sshd.service")]);
+        assert_eq!(
+            expand_placeholders("Start $(unit", &details),
+            "Start $(unit"
+        );
+        assert_eq!(
+            expand_placeholders("ends with $(", &details),
+            "ends with $("
+        );
+    }
+
+    #[test]
+    fn does_not_expand_nested_placeholders() {
+        // The key runs to the first `)`, so the inner placeholder is part of
+        // an unknown key and the whole thing is kept.
+        let details = details(&[("unit", "sshd.service")]);
+        assert_eq!(expand_placeholders("$(a$(unit))", &details), "$(a$(unit))");
+    }
+
+    #[test]
+    fn does_not_expand_values() {
+        let details = details(&[("device", "$(unit)"), ("unit", "sshd.service")]);
+        assert_eq!(
+            expand_placeholders("Mount $(device)", &details),
+            "Mount $(unit)"
+        );
+    }
+
+    #[test]
+    fn handles_multibyte_text() {
+        let details = details(&[("drive.name", "Größe 1 TB")]);
+        assert_eq!(
+            expand_placeholders("Einhängen von „$(drive.name)“ – ok", &details),
+            "Einhängen von „Größe 1 TB“ – ok"
+        );
+    }
+}
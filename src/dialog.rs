@@ -0,0 +1,328 @@
+//! The authentication dialog's window, declared in `data/`.
+//!
+//! `BadgedAuthDialog` is the window and its header: a `GtkWindow` from
+//! `dialog.ui`, or with the `adwaita` feature an `AdwWindow` from
+//! `dialog-adwaita.ui`, which also shows errors in a banner. Both hold a
+//! `BadgedAuthContent` from `dialog-content.ui`, the widgets `ui` drives.
+//!
+//! The request's message, caller and icon are properties of the dialog, and
+//! the two buttons that end an attempt are its signals (`authenticate` and
+//! `cancel`), so the rest of the UI doesn't reach into the templates for them.
+
+use gtk4::glib;
+use gtk4::prelude::*;
+use gtk4::subclass::prelude::*;
+#[cfg(feature = "adwaita")]
+use libadwaita as adw;
+
+pub use content::BadgedAuthContent;
+
+mod imp {
+    use std::cell::RefCell;
+    use std::sync::OnceLock;
+
+    use gtk4::glib;
+    use gtk4::glib::subclass::Signal;
+    use gtk4::prelude::*;
+    use gtk4::subclass::prelude::*;
+    use gtk4::CompositeTemplate;
+    #[cfg(feature = "adwaita")]
+    use libadwaita::{self as adw, subclass::prelude::*};
+
+    #[derive(Default, CompositeTemplate, glib::Properties)]
+    #[cfg_attr(not(feature = "adwaita"), template(file = "../data/dialog.ui"))]
+    #[cfg_attr(feature = "adwaita", template(file = "../data/dialog-adwaita.ui"))]
+    #[properties(wrapper_type = super::BadgedAuthDialog)]
+    pub struct BadgedAuthDialog {
+        /// What the action wants to do, as polkit worded it.
+        #[property(get, set)]
+        message: RefCell<String>,
+        /// The program that asked; the line is hidden while unset.
+        #[property(get, set, nullable)]
+        requested_by: RefCell<Option<String>>,
+        /// Themed icon shown next to the title.
+        #[property(get, set)]
+        action_icon: RefCell<String>,
+        /// Why the last attempt failed, until the next one. Only the
+        /// Adwaita dialog shows it, in a banner; the status line says the
+        /// same in both.
+        #[property(get, set = Self::set_error, nullable)]
+        error: RefCell<Option<String>>,
+
+        #[template_child]
+        pub content: TemplateChild<super::BadgedAuthContent>,
+        #[cfg(feature = "adwaita")]
+        #[template_child]
+        banner: TemplateChild<adw::Banner>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for BadgedAuthDialog {
+        const NAME: &'static str = "BadgedAuthDialog";
+        type Type = super::BadgedAuthDialog;
+        #[cfg(not(feature = "adwaita"))]
+        type ParentType = gtk4::Window;
+        #[cfg(feature = "adwaita")]
+        type ParentType = adw::Window;
+
+        fn class_init(klass: &mut Self::Class) {
+            super::BadgedAuthContent::ensure_type();
+            klass.bind_template();
+            klass.bind_template_instance_callbacks();
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    #[glib::derived_properties]
+    impl ObjectImpl for BadgedAuthDialog {
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: OnceLock<Vec<Signal>> = OnceLock::new();
+            SIGNALS.get_or_init(|| {
+                vec![
+                    Signal::builder("authenticate").build(),
+                    Signal::builder("cancel").build(),
+                ]
+            })
+        }
+
+        fn constructed(&self) {
+            self.parent_constructed();
+            // Enter anywhere in the dialog authenticates (while the button
+            // is sensitive).
+            self.obj()
+                .set_default_widget(Some(&*self.content.imp().auth_button));
+        }
+    }
+
+    impl WidgetImpl for BadgedAuthDialog {}
+
+    impl WindowImpl for BadgedAuthDialog {
+        // Closing the window takes the same path as Cancel; the window itself
+        // is kept around for the next request.
+        fn close_request(&self) -> glib::Propagation {
+            self.obj().emit_cancel();
+            glib::Propagation::Stop
+        }
+    }
+
+    #[cfg(feature = "adwaita")]
+    impl AdwWindowImpl for BadgedAuthDialog {}
+
+    impl BadgedAuthDialog {
+        fn set_error(&self, error: Option<String>) {
+            #[cfg(feature = "adwaita")]
+            {
+                if let Some(error) = &error {
+                    self.banner.set_title(error);
+                }
+                self.banner.set_revealed(error.is_some());
+            }
+            self.error.replace(error);
+        }
+    }
+}
+
+#[cfg(not(feature = "adwaita"))]
+glib::wrapper! {
+    pub struct BadgedAuthDialog(ObjectSubclass<imp::BadgedAuthDialog>)
+        @extends gtk4::Window, gtk4::Widget,
+        @implements gtk4::Accessible, gtk4::Buildable, gtk4::ConstraintTarget,
+            gtk4::Native, gtk4::Root, gtk4::ShortcutManager;
+}
+
+#[cfg(feature = "adwaita")]
+glib::wrapper! {
+    pub struct BadgedAuthDialog(ObjectSubclass<imp::BadgedAuthDialog>)
+        @extends adw::Window, gtk4::Window, gtk4::Widget,
+        @implements gtk4::Accessible, gtk4::Buildable, gtk4::ConstraintTarget,
+            gtk4::Native, gtk4::Root, gtk4::ShortcutManager;
+}
+
+#[gtk4::template_callbacks]
+impl BadgedAuthDialog {
+    pub fn new(app: &gtk4::Application, action_icon: &str) -> Self {
+        glib::Object::builder()
+            .property("application", app)
+            .property("action-icon", action_icon)
+            .build()
+    }
+
+    /// The widgets inside the window.
+    pub fn content(&self) -> &BadgedAuthContent {
+        &self.imp().content
+    }
+
+    #[template_callback]
+    fn authenticate(&self) {
+        self.emit_by_name::<()>("authenticate", &[]);
+    }
+
+    /// Give up on the request, as the Cancel button does.
+    #[template_callback]
+    pub fn emit_cancel(&self) {
+        self.emit_by_name::<()>("cancel", &[]);
+    }
+
+    pub fn connect_authenticate<F: Fn(&Self) + 'static>(&self, f: F) -> glib::SignalHandlerId {
+        self.connect_local("authenticate", false, move |values| {
+            let dialog = values[0]
+                .get::<Self>()
+                .expect("authenticate emitted by a dialog");
+            f(&dialog);
+            None
+        })
+    }
+
+    pub fn connect_cancel<F: Fn(&Self) + 'static>(&self, f: F) -> glib::SignalHandlerId {
+        self.connect_local("cancel", false, move |values| {
+            let dialog = values[0].get::<Self>().expect("cancel emitted by a dialog");
+            f(&dialog);
+            None
+        })
+    }
+}
+
+/// The window's body: the request, the status and the answer fields.
+mod content {
+    use gtk4::glib;
+    use gtk4::prelude::*;
+
+    mod imp {
+        use std::cell::RefCell;
+        use std::sync::OnceLock;
+
+        use gtk4::glib;
+        use gtk4::glib::subclass::Signal;
+        use gtk4::prelude::*;
+        use gtk4::subclass::prelude::*;
+        use gtk4::CompositeTemplate;
+
+        #[derive(Default, CompositeTemplate, glib::Properties)]
+        #[template(file = "../data/dialog-content.ui")]
+        #[properties(wrapper_type = super::BadgedAuthContent)]
+        pub struct BadgedAuthContent {
+            #[property(get, set)]
+            message: RefCell<String>,
+            #[property(get, set = Self::set_requested_by, nullable)]
+            requested_by: RefCell<Option<String>>,
+
+            #[template_child]
+            pub requested_by_label: TemplateChild<gtk4::Label>,
+            #[template_child]
+            pub details_expander: TemplateChild<gtk4::Expander>,
+            #[template_child]
+            pub details_grid: TemplateChild<gtk4::Grid>,
+            #[template_child]
+            pub report_button: TemplateChild<gtk4::Button>,
+            #[template_child]
+            pub status_icon: TemplateChild<gtk4::Box>,
+            #[template_child]
+            pub status_icon_image: TemplateChild<gtk4::Image>,
+            #[template_child]
+            pub status_icon_label: TemplateChild<gtk4::Label>,
+            #[template_child]
+            pub fingerprint_status: TemplateChild<gtk4::Label>,
+            #[template_child]
+            pub fingerprint_countdown: TemplateChild<gtk4::ProgressBar>,
+            #[template_child]
+            pub extra_status: TemplateChild<gtk4::Box>,
+            #[template_child]
+            pub retry_button: TemplateChild<gtk4::Button>,
+            #[template_child]
+            pub fingerprint_again_button: TemplateChild<gtk4::Button>,
+            #[template_child]
+            pub separator_label: TemplateChild<gtk4::Label>,
+            #[template_child]
+            pub more_options_button: TemplateChild<gtk4::Button>,
+            #[template_child]
+            pub user_revealer: TemplateChild<gtk4::Revealer>,
+            #[template_child]
+            pub user_dropdown: TemplateChild<gtk4::DropDown>,
+            #[template_child]
+            pub user_entry_box: TemplateChild<gtk4::Box>,
+            #[template_child]
+            pub user_entry: TemplateChild<gtk4::Entry>,
+            #[template_child]
+            pub answered_prompts: TemplateChild<gtk4::Box>,
+            #[template_child]
+            pub password_box: TemplateChild<gtk4::Box>,
+            #[template_child]
+            pub password_label: TemplateChild<gtk4::Label>,
+            #[template_child]
+            pub password_entry: TemplateChild<gtk4::PasswordEntry>,
+            #[template_child]
+            pub pin_pad: TemplateChild<gtk4::Grid>,
+            #[template_child]
+            pub auth_button: TemplateChild<gtk4::Button>,
+        }
+
+        #[glib::object_subclass]
+        impl ObjectSubclass for BadgedAuthContent {
+            const NAME: &'static str = "BadgedAuthContent";
+            type Type = super::BadgedAuthContent;
+            type ParentType = gtk4::Box;
+
+            fn class_init(klass: &mut Self::Class) {
+                klass.bind_template();
+                klass.bind_template_instance_callbacks();
+            }
+
+            fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+                obj.init_template();
+            }
+        }
+
+        #[glib::derived_properties]
+        impl ObjectImpl for BadgedAuthContent {
+            fn signals() -> &'static [Signal] {
+                static SIGNALS: OnceLock<Vec<Signal>> = OnceLock::new();
+                SIGNALS.get_or_init(|| {
+                    vec![
+                        Signal::builder("authenticate").build(),
+                        Signal::builder("cancel").build(),
+                    ]
+                })
+            }
+        }
+
+        impl WidgetImpl for BadgedAuthContent {}
+
+        impl BoxImpl for BadgedAuthContent {}
+
+        impl BadgedAuthContent {
+            fn set_requested_by(&self, caller: Option<String>) {
+                match &caller {
+                    Some(caller) => self
+                        .requested_by_label
+                        .set_label(&format!("Requested by: {caller}")),
+                    None => self.requested_by_label.set_label(""),
+                }
+                self.requested_by_label.set_visible(caller.is_some());
+                self.requested_by.replace(caller);
+            }
+        }
+    }
+
+    glib::wrapper! {
+        pub struct BadgedAuthContent(ObjectSubclass<imp::BadgedAuthContent>)
+            @extends gtk4::Box, gtk4::Widget,
+            @implements gtk4::Accessible, gtk4::Buildable, gtk4::ConstraintTarget,
+                gtk4::Orientable;
+    }
+
+    #[gtk4::template_callbacks]
+    impl BadgedAuthContent {
+        #[template_callback]
+        fn authenticate_clicked(&self) {
+            self.emit_by_name::<()>("authenticate", &[]);
+        }
+
+        #[template_callback]
+        fn cancel_clicked(&self) {
+            self.emit_by_name::<()>("cancel", &[]);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Environment checks for `badged doctor`, bug reports and first-run setup.
+
+use std::fmt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use gtk4::gio;
+use gtk4::prelude::*;
+
+use crate::config::{self, Config};
+
+/// Where distributions install polkit's setuid helper.
+pub const HELPER_PATHS: &[&str] = &[
+    "/usr/lib/polkit-1/polkit-agent-helper-1",
+    "/usr/libexec/polkit-1/polkit-agent-helper-1",
+    "/usr/libexec/polkit-agent-helper-1",
+    "/usr/lib/policykit-1/polkit-agent-helper-1",
+];
+
+/// Socket polkit 126 and later use to start the helper through systemd
+/// instead of relying on a setuid binary.
+const HELPER_SOCKET: &str = "/run/polkit/agent-helper.socket";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tag = match self.status {
+            Status::Ok => "ok",
+            Status::Warning => "warn",
+            Status::Error => "FAIL",
+        };
+        write!(f, "[{tag:>4}] {}: {}", self.name, self.detail)
+    }
+}
+
+fn check(name: &'static str, status: Status, detail: impl Into<String>) -> Check {
+    Check {
+        name,
+        status,
+        detail: detail.into(),
+    }
+}
+
+pub fn run_checks() -> Vec<Check> {
+    vec![
+        check_helper(),
+        check_display(),
+        check_polkitd(),
+        check_config(),
+        check_fprintd(),
+    ]
+}
+
+/// Print all checks; returns false if any of them failed.
+pub fn print_report() -> bool {
+    let checks = run_checks();
+    for check in &checks {
+        println!("{check}");
+    }
+    checks.iter().all(|check| check.status != Status::Error)
+}
+
+/// libpolkit-agent spawns the helper from the path it was built with, so
+/// this can only tell whether one is installed, not choose it. Only a
+/// helper that is there but clearly not setuid root is an error; one that
+/// isn't in the usual places may still be where libpolkit-agent looks.
+pub fn check_helper() -> Check {
+    if Path::new(HELPER_SOCKET).exists() {
+        return check(
+            "polkit helper",
+            Status::Ok,
+            format!("socket-activated ({HELPER_SOCKET})"),
+        );
+    }
+    let Some(path) = HELPER_PATHS
+        .iter()
+        .map(Path::new)
+        .find(|path| path.exists())
+    else {
+        return check(
+            "polkit helper",
+            Status::Warning,
+            "polkit-agent-helper-1 not found in the usual places; is polkit installed?",
+        );
+    };
+
+    match path.metadata() {
+        Ok(meta) if meta.uid() == 0 && meta.mode() & 0o4000 != 0 => check(
+            "polkit helper",
+            Status::Ok,
+            format!("{} (setuid root)", path.display()),
+        ),
+        Ok(_) => check(
+            "polkit helper",
+            Status::Error,
+            format!("{} is not setuid root", path.display()),
+        ),
+        Err(err) => check(
+            "polkit helper",
+            Status::Warning,
+            format!("{}: {err}", path.display()),
+        ),
+    }
+}
+
+fn check_display() -> Check {
+    match (
+        std::env::var("WAYLAND_DISPLAY").ok(),
+        std::env::var("DISPLAY").ok(),
+    ) {
+        (Some(wayland), _) => check("display", Status::Ok, format!("Wayland ({wayland})")),
+        (None, Some(x11)) => check("display", Status::Ok, format!("X11 ({x11})")),
+        (None, None) => check(
+            "display",
+            Status::Warning,
+            "neither WAYLAND_DISPLAY nor DISPLAY is set in this environment",
+        ),
+    }
+}
+
+fn check_polkitd() -> Check {
+    let connection = match gio::bus_get_sync(gio::BusType::System, gio::Cancellable::NONE) {
+        Ok(connection) => connection,
+        Err(err) => return check("polkitd", Status::Error, format!("no system bus: {err}")),
+    };
+
+    let reply = connection.call_sync(
+        Some("org.freedesktop.DBus"),
+        "/org/freedesktop/DBus",
+        "org.freedesktop.DBus",
+        "NameHasOwner",
+        Some(&("org.freedesktop.PolicyKit1",).to_variant()),
+        None,
+        gio::DBusCallFlags::NONE,
+        1000,
+        gio::Cancellable::NONE,
+    );
+    match reply.ok().and_then(|reply| reply.get::<(bool,)>()) {
+        Some((true,)) => check(
+            "polkitd",
+            Status::Ok,
+            "org.freedesktop.PolicyKit1 is running",
+        ),
+        Some((false,)) => check(
+            "polkitd",
+            Status::Warning,
+            "org.freedesktop.PolicyKit1 is not running yet (it is usually bus-activated)",
+        ),
+        None => check("polkitd", Status::Error, "could not query the system bus"),
+    }
+}
+
+fn check_config() -> Check {
+    let Some(path) = config::config_path() else {
+        return check(
+            "config",
+            Status::Warning,
+            "cannot locate the config directory",
+        );
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => match toml::from_str::<Config>(&contents) {
+            Ok(_) => check("config", Status::Ok, path.display().to_string()),
+            Err(err) => check(
+                "config",
+                Status::Error,
+                format!("{} is invalid: {err}", path.display()),
+            ),
+        },
+        Err(_) => check(
+            "config",
+            Status::Ok,
+            format!("{} not present, using defaults", path.display()),
+        ),
+    }
+}
+
+/// Whether fprintd is installed, i.e. a fingerprint reader could be in use.
+pub fn fprintd_installed() -> bool {
+    ["/usr/libexec/fprintd", "/usr/lib/fprintd/fprintd"]
+        .iter()
+        .any(|path| Path::new(path).exists())
+}
+
+fn check_fprintd() -> Check {
+    if fprintd_installed() {
+        check("fingerprint", Status::Ok, "fprintd is installed")
+    } else {
+        check(
+            "fingerprint",
+            Status::Ok,
+            "fprintd not installed, password only",
+        )
+    }
+}
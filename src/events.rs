@@ -0,0 +1,106 @@
+//! Line-delimited JSON mirror of the authentication lifecycle (`--json-events`).
+//!
+//! One object per line on stdout, for status bars and scripts. Secrets are
+//! never part of a [`UiEvent`], so nothing sensitive can end up here.
+
+use crate::listener::UiEvent;
+
+/// Print `event` as a JSON line, if it is part of the public lifecycle.
+pub fn print_json(request_id: u64, event: &UiEvent) {
+    if let Some(line) = to_json(request_id, event) {
+        println!("{line}");
+    }
+}
+
+fn to_json(request_id: u64, event: &UiEvent) -> Option<String> {
+    let body = match event {
+        UiEvent::ShowDialog {
+            action_id,
+            message,
+            users,
+            ..
+        } => {
+            let users: Vec<String> = users.iter().map(|user| json_string(user)).collect();
+            format!(
+                r#""event":"request","action_id":{},"message":{},"users":[{}]"#,
+                json_string(action_id),
+                json_string(message),
+                users.join(",")
+            )
+        }
+        UiEvent::PasswordNeeded {
+            prompt_id, echo_on, ..
+        } => {
+            format!(r#""event":"prompt","prompt_id":{prompt_id},"echo_on":{echo_on}"#)
+        }
+        UiEvent::PamInfo(text) => {
+            format!(
+                r#""event":"message","kind":"info","text":{}"#,
+                json_string(text)
+            )
+        }
+        UiEvent::PamError(text) => {
+            format!(
+                r#""event":"message","kind":"error","text":{}"#,
+                json_string(text)
+            )
+        }
+        UiEvent::AuthFailed => r#""event":"attempt-failed""#.to_owned(),
+        UiEvent::HelperStalled => r#""event":"stalled""#.to_owned(),
+        UiEvent::LockedOut { seconds } => {
+            format!(r#""event":"locked-out","seconds":{seconds}"#)
+        }
+        UiEvent::RequestFailed { reason, .. } => {
+            format!(r#""event":"error","reason":{}"#, json_string(reason))
+        }
+        UiEvent::ActionDenied { action_id } => {
+            format!(r#""event":"denied","action_id":{}"#, json_string(action_id))
+        }
+        UiEvent::AuthComplete { success } => {
+            format!(r#""event":"complete","success":{success}"#)
+        }
+        UiEvent::ThemeChanged(_) => return None,
+    };
+    Some(format!(r#"{{"request_id":{request_id},{body}}}"#))
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::json_string;
+
+    #[test]
+    fn quotes_plain_text() {
+        assert_eq!(json_string("gparted"), r#""gparted""#);
+        assert_eq!(json_string(""), r#""""#);
+    }
+
+    #[test]
+    fn escapes_quotes_backslashes_and_controls() {
+        assert_eq!(json_string(r#"say "hi"\"#), r#""say \"hi\"\\""#);
+        assert_eq!(json_string("a\nb\tc\r"), r#""a\nb\tc\r""#);
+        assert_eq!(json_string("bell\u{7}"), r#""bell\u0007""#);
+    }
+
+    #[test]
+    fn keeps_multibyte_text() {
+        assert_eq!(json_string("José ✓"), "\"José ✓\"");
+    }
+}
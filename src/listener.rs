@@ -1,14 +1,16 @@
 //! Polkit agent listener — GObject subclass of PolkitAgentListener.
 //!
 //! Uses glib 0.20 (matching polkit-agent-rs) for GObject subclassing.
-//! Communicates with the GTK4 UI via mpsc channels and Rc<SharedState>.
+//! Communicates with the GTK4 UI via an event channel and Rc<SharedState>.
 
 use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::rc::{Rc, Weak};
-use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 use glib::prelude::*;
 use glib::subclass::prelude::*;
+use glib::translate::{from_glib_none, IntoGlib, IntoGlibPtr, ToGlibPtr};
 
 use polkit_agent_rs::gio;
 use polkit_agent_rs::gio::prelude::*;
@@ -17,23 +19,76 @@ use polkit_agent_rs::subclass::ListenerImpl;
 use polkit_agent_rs::traits::ListenerExt;
 use polkit_agent_rs::{RegisterFlags, Session};
 
+use crate::auth::{self, Completion, Conversation, Next};
+use crate::caller;
+use crate::config::Config;
+use crate::control::Control;
+use crate::pam::AuthMethod;
+use crate::secret::SecretString;
+use crate::stats::{self, Outcome};
+use crate::theme::Theme;
+use crate::transcript::Transcript;
+
+/// Window over which `rate_limit` requests per subject are counted.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
 /// Events sent from the listener to the GTK4 UI.
 #[derive(Debug, Clone)]
 pub enum UiEvent {
     ShowDialog {
         request_id: u64,
+        action_id: String,
         message: String,
+        icon_name: String,
         users: Vec<String>,
+        /// Index into `users` of the identity the first session runs as.
+        selected_user: usize,
+        /// Recognized request details as `(label, value)` rows.
+        details: Vec<(String, String)>,
+        /// The process that asked for authorization, if it could be identified.
+        requested_by: Option<String>,
     },
     PamInfo(String),
     PamError(String),
-    PasswordNeeded,
-    AuthComplete {
-        success: bool,
+    /// PAM asked for a response. `echo_on` prompts (one-time codes and the
+    /// like) are not secret and are typed in the clear. A conversation may
+    /// chain several prompts (current, new and retyped password); the
+    /// response must name the `prompt_id` it answers.
+    PasswordNeeded {
+        prompt_id: u64,
+        prompt: String,
+        echo_on: bool,
+    },
+    AuthFailed,
+    /// The helper has been silent for `helper_timeout` without asking for input.
+    HelperStalled,
+    /// Too many failed attempts; the next one is allowed after `seconds`.
+    LockedOut {
+        seconds: u64,
     },
-    PolkitCancelled {
+    /// The request could not be serviced at all.
+    RequestFailed {
         request_id: u64,
+        reason: String,
+    },
+    /// The request was turned down by a `deny` pattern without a dialog.
+    ActionDenied {
+        action_id: String,
     },
+    AuthComplete {
+        success: bool,
+    },
+    /// Restyle the dialog with another preset.
+    ThemeChanged(Theme),
+}
+
+/// The polkit-supplied description of what is being authorized.
+pub struct AuthRequest {
+    pub action_id: String,
+    pub message: String,
+    pub icon_name: String,
+    pub cookie: String,
+    pub details: BTreeMap<String, String>,
 }
 
 #[derive(Clone)]
@@ -44,256 +99,1248 @@ struct IdentityChoice {
 
 struct ActiveRequest {
     request_id: u64,
-    attempt_id: u64,
+    action_id: String,
     cookie: String,
     selected_user: usize,
+    /// None of polkit's identities resolved, so the user types an account
+    /// name instead; `choices` holds whatever they entered last.
+    manual_user: bool,
+    /// Attempts, prompts and failures so far.
+    conversation: Conversation,
+    choices: Vec<IdentityChoice>,
+    /// `None` until a manually entered user name has been accepted.
+    session: Option<Session>,
+    /// Set when `record_transcripts` is on and the file could be created.
+    transcript: Option<Transcript>,
+    task: gio::Task<bool>,
+    /// Keeps the cookie out of the log for as long as the request lives.
+    _redaction: crate::logging::Redaction,
+}
+
+impl ActiveRequest {
+    /// Name of the identity being authenticated, empty if none was chosen yet.
+    fn user(&self) -> &str {
+        self.choices
+            .get(self.selected_user)
+            .map_or("", |choice| choice.user.as_str())
+    }
+}
+
+/// A validated request waiting for the one on screen to finish.
+struct PendingRequest {
+    request_id: u64,
+    request: AuthRequest,
     choices: Vec<IdentityChoice>,
-    session: Session,
     task: gio::Task<bool>,
+    redaction: crate::logging::Redaction,
 }
 
 struct SharedInner {
     next_request_id: u64,
     active: Option<ActiveRequest>,
+    /// Requests polkitd started while another was active, oldest first.
+    queue: VecDeque<PendingRequest>,
+    /// When recent requests from each subject process arrived, keyed by pid
+    /// and executable, for the rate limit.
+    recent: HashMap<(u32, String), VecDeque<Instant>>,
+    /// Whether our login session is in the foreground, per logind.
+    session_active: bool,
 }
 
 /// State shared between listener and UI for session control.
 pub struct SharedState {
-    event_tx: mpsc::Sender<UiEvent>,
+    event_tx: async_channel::Sender<UiEvent>,
+    json_events: bool,
+    control: Rc<Control>,
+    /// Swapped wholesale by [`SharedState::reload_config`].
+    config: RefCell<Rc<Config>>,
     inner: RefCell<SharedInner>,
 }
 
 impl SharedState {
-    pub fn new(event_tx: mpsc::Sender<UiEvent>) -> Rc<Self> {
+    pub fn new(
+        event_tx: async_channel::Sender<UiEvent>,
+        json_events: bool,
+        control: Rc<Control>,
+        config: Rc<Config>,
+    ) -> Rc<Self> {
         Rc::new(Self {
             event_tx,
+            json_events,
+            control,
+            config: RefCell::new(config),
             inner: RefCell::new(SharedInner {
                 next_request_id: 1,
                 active: None,
+                queue: VecDeque::new(),
+                recent: HashMap::new(),
+                session_active: true,
             }),
         })
     }
 
+    pub fn config(&self) -> Rc<Config> {
+        self.config.borrow().clone()
+    }
+
+    /// Re-read the config file. Requests already on screen keep their
+    /// message; `app_id` and `bus_name` only take effect after a restart.
+    pub fn reload_config(&self) {
+        log!("[listener] Reloading configuration");
+        let config = Rc::new(Config::load());
+        // Also undoes a theme picked through the control interface.
+        self.set_theme(config.theme);
+        *self.config.borrow_mut() = config;
+    }
+
+    /// Switch the dialog to `theme` until the next reload or restart.
+    pub fn set_theme(&self, theme: Theme) {
+        log!("[listener] Switching to the {} theme", theme.name());
+        let _ = self.event_tx.try_send(UiEvent::ThemeChanged(theme));
+    }
+
+    /// Request ids and action ids of the active request and the queue, in order.
+    pub fn pending_requests(&self) -> Vec<(u64, String)> {
+        let inner = self.inner.borrow();
+        inner
+            .active
+            .iter()
+            .map(|active| (active.request_id, active.action_id.clone()))
+            .chain(
+                inner
+                    .queue
+                    .iter()
+                    .map(|pending| (pending.request_id, pending.request.action_id.clone())),
+            )
+            .collect()
+    }
+
     pub fn start_request(
         self: &Rc<Self>,
-        message: &str,
-        cookie: &str,
+        request: AuthRequest,
         identities: Vec<polkit::Identity>,
         task: gio::Task<bool>,
         cancellable: gio::Cancellable,
     ) {
-        let choices: Vec<IdentityChoice> = identities
-            .into_iter()
-            .filter_map(|identity| {
-                identity
-                    .downcast_ref::<polkit::UnixUser>()
-                    .and_then(|user| user.name())
-                    .map(|user| IdentityChoice {
-                        user: user.to_string(),
-                        identity,
-                    })
-            })
-            .collect();
-
-        if choices.is_empty() {
-            unsafe {
-                task.return_result(Err(glib::Error::new(
-                    glib::FileError::Failed,
-                    "No valid identities",
-                )))
-            };
-            return;
-        }
-
-        let users = choices.iter().map(|choice| choice.user.clone()).collect();
-        let session = Session::new(&choices[0].identity, cookie);
-
-        let (request_id, attempt_id, previous) = {
+        let request_id = {
             let mut inner = self.inner.borrow_mut();
             let request_id = inner.next_request_id;
             inner.next_request_id += 1;
+            request_id
+        };
+        let _scope = crate::logging::request_scope(request_id);
 
-            let active = ActiveRequest {
-                request_id,
-                attempt_id: 1,
-                cookie: cookie.to_owned(),
-                selected_user: 0,
-                choices,
-                session: session.clone(),
-                task,
-            };
-            let previous = inner.active.replace(active);
-            (request_id, 1, previous)
+        let identity_count = identities.len();
+        let choices = match validate_request(&request, identities) {
+            Ok(choices) => choices,
+            Err(err @ RequestError::InvalidIdentities { .. }) => {
+                log!("[listener] {err}; asking for a user name instead");
+                Vec::new()
+            }
+            Err(err) => {
+                // Everything but the cookie, which is a secret.
+                let detail_keys: Vec<&str> = request.details.keys().map(String::as_str).collect();
+                log!(
+                    "[listener] Rejecting request: {err} (action_id={:?}, message={:?}, \
+                     {identity_count} identities, details: {})",
+                    request.action_id,
+                    request.message,
+                    detail_keys.join(", ")
+                );
+                self.send(
+                    request_id,
+                    UiEvent::RequestFailed {
+                        request_id,
+                        reason: format!("Authentication could not be started: {err}"),
+                    },
+                );
+                unsafe { task.return_result(Err(err.into_glib_error(request_id))) };
+                return;
+            }
         };
 
-        if let Some(previous) = previous {
-            self.abort_request(previous, false);
+        // Cookies are unique per request, so a second one means polkitd
+        // re-sent it; answering it would put two helpers on one cookie.
+        if let Some(existing) = self.request_for_cookie(&request.cookie) {
+            log!(
+                "[listener] Rejecting request for {}: same cookie as request {existing}",
+                request.action_id
+            );
+            unsafe { task.return_result(Err(duplicate_error(request_id, existing))) };
+            return;
         }
 
-        let _ = self.event_tx.send(UiEvent::ShowDialog {
+        // A helper that isn't setuid root fails without a word; say why up
+        // front instead. Anything short of that (not found where we looked,
+        // unreadable) is left to the helper itself.
+        let helper = crate::doctor::check_helper();
+        if helper.status == crate::doctor::Status::Error {
+            log!("[listener] Rejecting request: {helper}");
+            let reason = format!("Authentication could not be started: {}", helper.detail);
+            self.send(
+                request_id,
+                UiEvent::RequestFailed {
+                    request_id,
+                    reason: reason.clone(),
+                },
+            );
+            unsafe { task.return_result(Err(failed_error(request_id, &reason))) };
+            return;
+        }
+
+        if self.config().is_denied(&request.action_id) {
+            log!(
+                "[listener] Denying request for {} by configuration",
+                request.action_id
+            );
+            self.send(
+                request_id,
+                UiEvent::ActionDenied {
+                    action_id: request.action_id.clone(),
+                },
+            );
+            unsafe { task.return_result(Err(denied_error(request_id, &request.action_id))) };
+            return;
+        }
+
+        if let Some(caller) = self.rate_limited(&request.details) {
+            log!(
+                "[listener] Rejecting request for {}: {caller} exceeded {} requests per minute",
+                request.action_id,
+                self.config().rate_limit
+            );
+            let active_id = self.inner.borrow().active.as_ref().map(|a| a.request_id);
+            if let Some(active_id) = active_id {
+                self.send(
+                    active_id,
+                    UiEvent::PamInfo(format!(
+                        "Ignored another authentication request from {caller}: \
+                         it is asking too often"
+                    )),
+                );
+            }
+            unsafe { task.return_result(Err(rate_limited_error(request_id, &caller))) };
+            return;
+        }
+
+        let redaction = crate::logging::register_secret(&request.cookie);
+
+        // polkitd's CancelAuthentication names a cookie; cancel exactly the
+        // request carrying it, whether on screen or still queued. Deferred to
+        // idle so it never runs inside one of our own borrows. libpolkit-agent
+        // cancels from the main loop, so the thread guard always holds.
+        let weak = glib::thread_guard::ThreadGuard::new(Rc::downgrade(self));
+        let cookie = request.cookie.clone();
+        let _ = cancellable.connect_cancelled(move |_| {
+            let weak = weak.into_inner();
+            glib::idle_add_local_once(move || {
+                if let Some(shared) = weak.upgrade() {
+                    shared.cancel_cookie(&cookie);
+                }
+            });
+        });
+
+        let pending = PendingRequest {
             request_id,
-            message: message.to_owned(),
-            users,
+            request,
+            choices,
+            task,
+            redaction,
+        };
+
+        // One dialog at a time; later requests wait their turn instead of
+        // tearing down the one the user is looking at.
+        let mut inner = self.inner.borrow_mut();
+        if inner.active.is_some() {
+            log!(
+                "[listener] Queuing request {request_id} behind the active one ({} waiting)",
+                inner.queue.len() + 1
+            );
+            inner.queue.push_back(pending);
+            return;
+        }
+        if !inner.session_active {
+            log!("[listener] Holding request {request_id} until the session is active again");
+            inner.queue.push_back(pending);
+            return;
+        }
+        drop(inner);
+        self.activate(pending);
+    }
+
+    /// Track logind's `Active` property for our session. Requests arriving
+    /// while another session is in the foreground wait in the queue.
+    pub fn set_session_active(self: &Rc<Self>, active: bool) {
+        let was_active = std::mem::replace(&mut self.inner.borrow_mut().session_active, active);
+        if was_active == active {
+            return;
+        }
+        if active {
+            log!("[listener] Session is active again");
+            self.activate_next();
+        } else {
+            log!("[listener] Session went inactive; holding new requests");
+        }
+    }
+
+    /// Count a request against its subject's budget. Returns a description
+    /// of the subject if it is over `rate_limit` requests per minute.
+    ///
+    /// The subject is keyed by pid and executable rather than its name,
+    /// which any program can change. Requests without a subject pid are
+    /// never limited, rather than all sharing one budget.
+    fn rate_limited(&self, details: &BTreeMap<String, String>) -> Option<String> {
+        let limit = self.config().rate_limit;
+        if limit == 0 {
+            return None;
+        }
+        let pid = caller::subject_pid(details)?;
+        let exe = caller::executable(pid).unwrap_or_default();
+
+        let now = Instant::now();
+        let mut inner = self.inner.borrow_mut();
+        inner.recent.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|time| now.duration_since(*time) > RATE_LIMIT_WINDOW)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
         });
+        let times = inner.recent.entry((pid, exe.clone())).or_default();
+        if times.len() >= limit as usize {
+            let name = caller::program_name(pid).unwrap_or(exe);
+            return Some(format!("{name} (pid {pid})"));
+        }
+        times.push_back(now);
+        None
+    }
 
-        self.attach_session(request_id, attempt_id, &session);
+    /// Put `pending` on screen and start its first session.
+    fn activate(self: &Rc<Self>, pending: PendingRequest) {
+        let PendingRequest {
+            request_id,
+            request,
+            choices,
+            task,
+            redaction,
+        } = pending;
+        let _scope = crate::logging::request_scope(request_id);
 
-        let tx = self.event_tx.clone();
-        let _ = cancellable.connect_cancelled(move |_| {
-            let _ = tx.send(UiEvent::PolkitCancelled { request_id });
+        let manual_user = choices.is_empty();
+        let selected_user = self.default_choice(&choices);
+        let first_user = choices
+            .get(selected_user)
+            .map(|choice| choice.user.clone())
+            .unwrap_or_default();
+        let message = self.display_message(&request, &first_user);
+        let details = crate::details::rows(&request.action_id, &request.details);
+        let requested_by = caller::caller_pid(&request.details).and_then(caller::describe);
+        let AuthRequest {
+            action_id,
+            icon_name,
+            cookie,
+            ..
+        } = request;
+
+        stats::record(&action_id, Outcome::Prompted);
+
+        self.control.authentication_started(&action_id, &first_user);
+
+        let users = choices.iter().map(|choice| choice.user.clone()).collect();
+        let session = choices
+            .get(selected_user)
+            .map(|choice| Session::new(&choice.identity, &cookie));
+
+        let transcript = self
+            .config()
+            .record_transcripts
+            .then(|| Transcript::start(request_id, &action_id))
+            .flatten();
+
+        let conversation = Conversation::new(Instant::now());
+        let attempt_id = conversation.attempt_id();
+        self.inner.borrow_mut().active = Some(ActiveRequest {
+            request_id,
+            action_id: action_id.clone(),
+            cookie,
+            selected_user,
+            manual_user,
+            conversation,
+            choices,
+            session: session.clone(),
+            transcript,
+            task,
+            _redaction: redaction,
         });
 
-        session.initiate();
+        self.send(
+            request_id,
+            UiEvent::ShowDialog {
+                request_id,
+                action_id,
+                message,
+                icon_name,
+                users,
+                selected_user,
+                details,
+                requested_by,
+            },
+        );
+
+        if let Some(session) = session {
+            self.run_session(request_id, attempt_id, &session);
+        }
     }
 
-    pub fn respond(&self, request_id: u64, password: &str) -> bool {
+    /// Which identity to try first: the configured `default_user`, else the
+    /// user running badged, else whatever polkit listed first (often root).
+    fn default_choice(&self, choices: &[IdentityChoice]) -> usize {
+        let current = glib::user_name();
+        let preferred = [
+            self.config().default_user.clone(),
+            current.into_string().ok(),
+        ];
+        preferred
+            .into_iter()
+            .flatten()
+            .find_map(|name| choices.iter().position(|choice| choice.user == name))
+            .unwrap_or(0)
+    }
+
+    /// Show the next queued request, if the dialog is free and someone is
+    /// there to see it.
+    fn activate_next(self: &Rc<Self>) {
+        let next = {
+            let mut inner = self.inner.borrow_mut();
+            if inner.active.is_some() {
+                return;
+            }
+            if inner.session_active {
+                inner.queue.pop_front()
+            } else {
+                None
+            }
+        };
+        if let Some(pending) = next {
+            self.activate(pending);
+        }
+    }
+
+    /// Answer prompt `prompt_id`. Refused unless it is the prompt the
+    /// session is waiting on, so an answer can't land on a later prompt.
+    pub fn respond(&self, request_id: u64, prompt_id: u64, password: &SecretString) -> bool {
+        let _scope = crate::logging::request_scope(request_id);
+        // The helper reads the answer as one line of C string.
+        let Some(response) = password.nul_terminated() else {
+            log!("[listener] Refusing an answer that contains a NUL byte");
+            return false;
+        };
         let session = {
-            let inner = self.inner.borrow_mut();
+            let mut inner = self.inner.borrow_mut();
             inner
                 .active
-                .as_ref()
-                .filter(|active| active.request_id == request_id)
-                .map(|active| active.session.clone())
+                .as_mut()
+                .filter(|active| active.request_id == request_id && active.session.is_some())
+                .and_then(|active| {
+                    if !active.conversation.respond(prompt_id, Instant::now()) {
+                        log!(
+                            "[listener] Ignoring an answer to prompt {prompt_id}: {:?}",
+                            active.conversation.state()
+                        );
+                        return None;
+                    }
+                    if let Some(transcript) = active.transcript.as_mut() {
+                        transcript.record("response", "<redacted>");
+                    }
+                    active.session.clone()
+                })
         };
 
         if let Some(session) = session {
-            session.response(password);
+            // Not `Session::response`: converting the `&str` there leaves an
+            // unwiped copy behind. libpolkit-agent writes this buffer
+            // straight to the helper's stdin without keeping it.
+            unsafe {
+                polkit_agent_rs_sys::polkit_agent_session_response(
+                    session.to_glib_none().0,
+                    response.as_ptr().cast(),
+                );
+            }
             true
         } else {
             false
         }
     }
 
-    pub fn cancel_request(&self, request_id: u64) -> bool {
-        let active = {
+    pub fn cancel_request(self: &Rc<Self>, request_id: u64) -> bool {
+        let _scope = crate::logging::request_scope(request_id);
+        let (active, pending) = {
             let mut inner = self.inner.borrow_mut();
             match inner.active.as_ref() {
-                Some(active) if active.request_id == request_id => inner.active.take(),
-                _ => None,
+                Some(active) if active.request_id == request_id => (inner.active.take(), None),
+                _ => {
+                    let pending = inner
+                        .queue
+                        .iter()
+                        .position(|pending| pending.request_id == request_id)
+                        .and_then(|index| inner.queue.remove(index));
+                    (None, pending)
+                }
             }
         };
 
         if let Some(active) = active {
-            self.abort_request(active, true);
+            let error = cancelled_error(active.request_id);
+            self.abort_request(active, error);
+            self.activate_next();
+            true
+        } else if let Some(pending) = pending {
+            log!("[listener] Dropping queued request {request_id}");
+            drop_pending(pending);
             true
         } else {
             false
         }
     }
 
+    /// Fail every active and queued request with a cancelled reply, e.g. on
+    /// shutdown, so polkitd isn't left waiting on an agent that is going away.
+    pub fn cancel_all(&self) {
+        let (active, queue) = {
+            let mut inner = self.inner.borrow_mut();
+            (inner.active.take(), std::mem::take(&mut inner.queue))
+        };
+        if let Some(active) = active {
+            let error = cancelled_error(active.request_id);
+            self.abort_request(active, error);
+        }
+        for pending in queue {
+            drop_pending(pending);
+        }
+    }
+
+    /// Cancel the request, active or queued, that polkitd issued `cookie` for.
+    pub fn cancel_cookie(self: &Rc<Self>, cookie: &str) -> bool {
+        match self.request_for_cookie(cookie) {
+            Some(request_id) => {
+                log!("[listener] polkit cancelled request {request_id}");
+                self.cancel_request(request_id)
+            }
+            None => false,
+        }
+    }
+
+    /// The active or queued request polkitd issued `cookie` for.
+    fn request_for_cookie(&self, cookie: &str) -> Option<u64> {
+        let inner = self.inner.borrow();
+        inner
+            .active
+            .iter()
+            .map(|active| (active.request_id, active.cookie.as_str()))
+            .chain(
+                inner
+                    .queue
+                    .iter()
+                    .map(|pending| (pending.request_id, pending.request.cookie.as_str())),
+            )
+            .find(|(_, candidate)| *candidate == cookie)
+            .map(|(request_id, _)| request_id)
+    }
+
+    /// Index of the identity the active session authenticates as.
+    pub fn selected_user(&self, request_id: u64) -> Option<usize> {
+        let inner = self.inner.borrow();
+        inner
+            .active
+            .as_ref()
+            .filter(|active| active.request_id == request_id)
+            .map(|active| active.selected_user)
+    }
+
+    /// Restart the session as another identity. Refused while a response is
+    /// in flight, so the old helper can't consume a password meant for it.
     pub fn select_user(self: &Rc<Self>, request_id: u64, user_index: usize) -> bool {
-        let (session_to_cancel, session_to_start, attempt_id) = {
+        let _scope = crate::logging::request_scope(request_id);
+        let (session_to_cancel, cookie, identity) = {
             let mut inner = self.inner.borrow_mut();
             let active = match inner.active.as_mut() {
                 Some(active) if active.request_id == request_id => active,
                 _ => return false,
             };
 
-            if user_index >= active.choices.len() || user_index == active.selected_user {
+            if user_index >= active.choices.len()
+                || user_index == active.selected_user
+                || !active.conversation.can_switch_identity()
+            {
                 return false;
             }
 
+            // Retire the current attempt first so nothing it emits from here
+            // on is mistaken for the new session's output.
             active.selected_user = user_index;
-            active.attempt_id += 1;
+            active.conversation.retire_attempt();
+            (
+                active.session.clone(),
+                active.cookie.clone(),
+                active.choices[user_index].identity.clone(),
+            )
+        };
+
+        // Cancel the old helper before the new one is spawned.
+        if let Some(session) = session_to_cancel {
+            session.cancel();
+        }
+
+        self.start_session(request_id, &identity, &cookie)
+    }
+
+    /// Authenticate as a typed-in user name, for requests where none of
+    /// polkit's identities could be resolved. The name is looked up through
+    /// NSS before any session is started.
+    pub fn enter_user(self: &Rc<Self>, request_id: u64, name: &str) -> Result<(), String> {
+        let _scope = crate::logging::request_scope(request_id);
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("Enter a user name".to_owned());
+        }
+        let identity: polkit::Identity = polkit::UnixUser::new_for_name(name)
+            .map_err(|_| format!("Unknown user \"{name}\""))?
+            .upcast();
+
+        let (session_to_cancel, cookie) = {
+            let mut inner = self.inner.borrow_mut();
+            let active = match inner.active.as_mut() {
+                Some(active) if active.request_id == request_id && active.manual_user => active,
+                _ => return Err("The request is no longer active".to_owned()),
+            };
+            if active.conversation.is_locked_out() {
+                return Err("Too many failed attempts, please wait".to_owned());
+            }
+            if !active.conversation.can_switch_identity() {
+                return Err("Still authenticating, please wait".to_owned());
+            }
+
+            active.choices = vec![IdentityChoice {
+                user: name.to_owned(),
+                identity: identity.clone(),
+            }];
+            active.selected_user = 0;
+            active.conversation.retire_attempt();
+            (active.session.take(), active.cookie.clone())
+        };
+
+        log!("[listener] Authenticating as manually entered user {name}");
+        if let Some(session) = session_to_cancel {
+            session.cancel();
+        }
+        if self.start_session(request_id, &identity, &cookie) {
+            Ok(())
+        } else {
+            Err("The request is no longer active".to_owned())
+        }
+    }
+
+    /// Kill the current helper and start over for the same identity and
+    /// cookie, e.g. after the watchdog reported it stalled.
+    pub fn restart_session(self: &Rc<Self>, request_id: u64) -> bool {
+        let _scope = crate::logging::request_scope(request_id);
+        let (session_to_cancel, cookie, identity) = {
+            let mut inner = self.inner.borrow_mut();
+            let active = match inner.active.as_mut() {
+                Some(active)
+                    if active.request_id == request_id && !active.conversation.is_locked_out() =>
+                {
+                    active
+                }
+                _ => return false,
+            };
+            let Some(choice) = active.choices.get(active.selected_user) else {
+                return false;
+            };
+            let identity = choice.identity.clone();
+            active.conversation.retire_attempt();
+            (active.session.take(), active.cookie.clone(), identity)
+        };
 
-            let next_session = Session::new(&active.choices[user_index].identity, &active.cookie);
-            let previous_session = active.session.clone();
-            active.session = next_session.clone();
+        log!("[listener] Restarting the authentication session");
+        if let Some(session) = session_to_cancel {
+            session.cancel();
+        }
+        self.start_session(request_id, &identity, &cookie)
+    }
 
-            (previous_session, next_session, active.attempt_id)
+    /// Start a session for `identity` as the current attempt of `request_id`.
+    fn start_session(
+        self: &Rc<Self>,
+        request_id: u64,
+        identity: &polkit::Identity,
+        cookie: &str,
+    ) -> bool {
+        let _scope = crate::logging::request_scope(request_id);
+        let session_to_start = Session::new(identity, cookie);
+        let attempt_id = {
+            let mut inner = self.inner.borrow_mut();
+            let Some(active) = inner
+                .active
+                .as_mut()
+                .filter(|active| active.request_id == request_id)
+            else {
+                return false;
+            };
+            active.session = Some(session_to_start.clone());
+            active.conversation.attempt_id()
         };
 
-        self.attach_session(request_id, attempt_id, &session_to_start);
-        session_to_start.initiate();
-        session_to_cancel.cancel();
+        self.run_session(request_id, attempt_id, &session_to_start);
         true
     }
 
+    /// The message to show for `request`, after applying any configured template.
+    fn display_message(&self, request: &AuthRequest, user: &str) -> String {
+        let original = crate::details::expand_placeholders(&request.message, &request.details);
+        let config = self.config();
+        let Some(template) = config.message_template(&request.action_id) else {
+            return original;
+        };
+
+        let program = caller::caller_pid(&request.details)
+            .and_then(caller::program_name)
+            .unwrap_or_else(|| "An application".to_owned());
+        let device = ["device", "drive", "drive.name"]
+            .iter()
+            .find_map(|key| request.details.get(*key))
+            .map(String::as_str)
+            .unwrap_or("a device");
+
+        fill_template(
+            template,
+            &[
+                ("message", &original),
+                ("program", &program),
+                ("user", user),
+                ("device", device),
+            ],
+        )
+    }
+
+    /// Forward `event` about `request_id` to the UI and the JSON event stream.
+    fn send(&self, request_id: u64, event: UiEvent) {
+        if self.json_events {
+            crate::events::print_json(request_id, &event);
+        }
+        let _ = self.event_tx.try_send(event);
+    }
+
+    /// Wire up `session` as attempt `attempt_id`, start it and watch it.
+    fn run_session(self: &Rc<Self>, request_id: u64, attempt_id: u64, session: &Session) {
+        let _scope = crate::logging::request_scope(request_id);
+        self.attach_session(request_id, attempt_id, session);
+        if let Some(active) = self.inner.borrow_mut().active.as_mut() {
+            let user = active.user().to_owned();
+            if let Some(transcript) = active.transcript.as_mut() {
+                transcript.record("attempt", &format!("{attempt_id} as {user}"));
+            }
+            active.conversation.attempt_started(Instant::now());
+        }
+        session.initiate();
+        self.watch_session(request_id, attempt_id);
+    }
+
+    /// Report the attempt as stalled once the helper has been silent for
+    /// `helper_timeout` while not waiting for the user. Stops with the attempt.
+    ///
+    /// Past `helper_deadline` the helper is considered hung: it is killed and
+    /// the request fails. A prompt left unanswered for `prompt_timeout` fails
+    /// the request the same way.
+    fn watch_session(self: &Rc<Self>, request_id: u64, attempt_id: u64) {
+        let config = self.config();
+        let timeout =
+            (config.helper_timeout > 0).then(|| Duration::from_secs(config.helper_timeout));
+        let deadline =
+            (config.helper_deadline > 0).then(|| Duration::from_secs(config.helper_deadline));
+        let prompt_timeout =
+            (config.prompt_timeout > 0).then(|| Duration::from_secs(config.prompt_timeout));
+        if timeout.is_none() && deadline.is_none() && prompt_timeout.is_none() {
+            return;
+        }
+        let weak = Rc::downgrade(self);
+        let mut reported = false;
+        glib::timeout_add_seconds_local(1, move || {
+            let _scope = crate::logging::request_scope(request_id);
+            let Some(shared) = active_attempt(&weak, request_id, attempt_id) else {
+                return glib::ControlFlow::Break;
+            };
+            let Some((waiting, silent)) = shared.inner.borrow().active.as_ref().map(|active| {
+                let conversation = &active.conversation;
+                (
+                    conversation.waiting_on_user(),
+                    conversation.silent_for(Instant::now()),
+                )
+            }) else {
+                return glib::ControlFlow::Break;
+            };
+            if waiting {
+                reported = false;
+                if let Some(limit) = prompt_timeout.filter(|limit| silent >= *limit) {
+                    shared.fail_request(
+                        request_id,
+                        &format!("No response was given within {} seconds.", limit.as_secs()),
+                    );
+                    return glib::ControlFlow::Break;
+                }
+                return glib::ControlFlow::Continue;
+            }
+            if let Some(deadline) = deadline.filter(|deadline| silent >= *deadline) {
+                shared.fail_request(
+                    request_id,
+                    &format!(
+                        "The authentication service did not respond within {} seconds.",
+                        deadline.as_secs()
+                    ),
+                );
+                return glib::ControlFlow::Break;
+            }
+            if !reported && timeout.is_some_and(|timeout| silent >= timeout) {
+                log!("[listener] Helper silent for {silent:?}, offering a restart");
+                shared.send(request_id, UiEvent::HelperStalled);
+                reported = true;
+            }
+            glib::ControlFlow::Continue
+        });
+    }
+
+    /// Note a message from the live attempt's helper.
+    fn message(&self, recoverable: bool) {
+        if let Some(active) = self.inner.borrow_mut().active.as_mut() {
+            active.conversation.message(Instant::now(), recoverable);
+        }
+    }
+
+    /// Add a line to the active request's transcript, if one is recorded.
+    fn record(&self, kind: &str, text: &str) {
+        if let Some(transcript) = self
+            .inner
+            .borrow_mut()
+            .active
+            .as_mut()
+            .and_then(|active| active.transcript.as_mut())
+        {
+            transcript.record(kind, text);
+        }
+    }
+
     fn attach_session(self: &Rc<Self>, request_id: u64, attempt_id: u64, session: &Session) {
-        let tx = self.event_tx.clone();
         let weak = Rc::downgrade(self);
-        session.connect_request(move |_sess, _prompt, _echo_on| {
-            if is_active_attempt(&weak, request_id, attempt_id) {
-                let _ = tx.send(UiEvent::PasswordNeeded);
+        session.connect_request(move |_sess, prompt, echo_on| {
+            let _scope = crate::logging::request_scope(request_id);
+            if let Some(shared) = active_attempt(&weak, request_id, attempt_id) {
+                let prompt_id = {
+                    let mut inner = shared.inner.borrow_mut();
+                    let Some(active) = inner.active.as_mut() else {
+                        return;
+                    };
+                    active.conversation.prompt(Instant::now())
+                };
+                shared.record(if echo_on { "prompt-echo" } else { "prompt" }, prompt);
+                shared.send(
+                    request_id,
+                    UiEvent::PasswordNeeded {
+                        prompt_id,
+                        prompt: prompt.to_owned(),
+                        echo_on,
+                    },
+                );
             }
         });
 
-        let tx = self.event_tx.clone();
         let weak = Rc::downgrade(self);
         session.connect_show_info(move |_sess, text| {
-            if is_active_attempt(&weak, request_id, attempt_id) {
-                let _ = tx.send(UiEvent::PamInfo(text.to_owned()));
+            let _scope = crate::logging::request_scope(request_id);
+            if let Some(shared) = active_attempt(&weak, request_id, attempt_id) {
+                shared.message(false);
+                shared.record("info", text);
+                shared.send(request_id, UiEvent::PamInfo(text.to_owned()));
             }
         });
 
-        let tx = self.event_tx.clone();
         let weak = Rc::downgrade(self);
         session.connect_show_error(move |_sess, text| {
-            if is_active_attempt(&weak, request_id, attempt_id) {
-                let _ = tx.send(UiEvent::PamError(text.to_owned()));
+            let _scope = crate::logging::request_scope(request_id);
+            if let Some(shared) = active_attempt(&weak, request_id, attempt_id) {
+                shared.message(AuthMethod::is_recoverable(text));
+                shared.record("error", text);
+                shared.send(request_id, UiEvent::PamError(text.to_owned()));
             }
         });
 
         let weak = Rc::downgrade(self);
         session.connect_completed(move |_sess, gained_auth| {
+            let _scope = crate::logging::request_scope(request_id);
+            if let Some(shared) = active_attempt(&weak, request_id, attempt_id) {
+                shared.record("completed", if gained_auth { "success" } else { "failure" });
+            }
             if let Some(shared) = weak.upgrade() {
                 shared.finish_from_session(request_id, attempt_id, gained_auth);
             }
         });
     }
 
-    fn finish_from_session(&self, request_id: u64, attempt_id: u64, gained_auth: bool) {
+    /// Act on the end of attempt `attempt_id`: answer polkitd on success,
+    /// otherwise retry, lock out or fail the request as the conversation
+    /// decides.
+    fn finish_from_session(self: &Rc<Self>, request_id: u64, attempt_id: u64, gained_auth: bool) {
+        let _scope = crate::logging::request_scope(request_id);
+        let config = self.config();
+        let limits = auth::Limits {
+            max_attempts: config.max_attempts,
+            lockout: Duration::from_secs(config.lockout_seconds),
+        };
+        let completion = {
+            let mut inner = self.inner.borrow_mut();
+            let Some(active) = inner.active.as_mut().filter(|active| {
+                active.request_id == request_id && active.conversation.attempt_id() == attempt_id
+            }) else {
+                return;
+            };
+            let completion = active
+                .conversation
+                .completed(gained_auth, limits, Instant::now());
+            if let Completion::Failed { counted, .. } = completion {
+                if counted {
+                    stats::record(&active.action_id, Outcome::Failed);
+                }
+                active.session = None;
+            }
+            completion
+        };
+
+        match completion {
+            Completion::Succeeded => self.succeed_request(request_id),
+            Completion::NeverRan => self.fail_request(
+                request_id,
+                "The authentication helper could not be started; run `badged doctor` for details.",
+            ),
+            Completion::Failed { next, .. } => self.after_failure(request_id, next, &config),
+        }
+    }
+
+    /// Answer polkitd that the active request was authenticated.
+    fn succeed_request(self: &Rc<Self>, request_id: u64) {
         let active = {
             let mut inner = self.inner.borrow_mut();
             match inner.active.as_ref() {
-                Some(active)
-                    if active.request_id == request_id && active.attempt_id == attempt_id =>
-                {
-                    inner.active.take()
-                }
+                Some(active) if active.request_id == request_id => inner.active.take(),
                 _ => None,
             }
         };
 
         if let Some(active) = active {
-            if gained_auth {
-                unsafe { active.task.return_result(Ok(true)) };
-            } else {
-                unsafe { active.task.return_result(Err(auth_failed_error())) };
+            stats::record(&active.action_id, Outcome::Succeeded);
+            self.control
+                .authentication_finished(&active.action_id, active.user(), true);
+            unsafe { active.task.return_result(Ok(true)) };
+            self.send(request_id, UiEvent::AuthComplete { success: true });
+            self.activate_next();
+        }
+    }
+
+    /// Give up on the active request and tell the user why.
+    fn fail_request(self: &Rc<Self>, request_id: u64, reason: &str) {
+        let _scope = crate::logging::request_scope(request_id);
+        let active = {
+            let mut inner = self.inner.borrow_mut();
+            match inner.active.as_ref() {
+                Some(active) if active.request_id == request_id => inner.active.take(),
+                _ => None,
             }
-            let _ = self.event_tx.send(UiEvent::AuthComplete {
-                success: gained_auth,
-            });
+        };
+        if let Some(active) = active {
+            log!("[listener] Request {request_id} failed: {reason}");
+            self.abort_request(active, failed_error(request_id, reason));
+            self.send(
+                request_id,
+                UiEvent::RequestFailed {
+                    request_id,
+                    reason: reason.to_owned(),
+                },
+            );
+            self.activate_next();
         }
     }
 
-    fn abort_request(&self, active: ActiveRequest, emit_ui_complete: bool) {
-        active.session.cancel();
-        unsafe { active.task.return_result(Err(cancelled_error())) };
-        if emit_ui_complete {
-            let _ = self.event_tx.send(UiEvent::AuthComplete { success: false });
+    /// Follow up on a failed attempt: start the next one for the selected
+    /// identity, keeping the request (and the dialog) alive, if
+    /// `max_attempts` allows another; by default it doesn't.
+    ///
+    /// After `max_attempts` failures input is locked out for
+    /// `lockout_seconds`, once; exhausting the budget again fails the request.
+    fn after_failure(self: &Rc<Self>, request_id: u64, next: Next, config: &Config) {
+        self.send(request_id, UiEvent::AuthFailed);
+        match next {
+            Next::Retry => self.next_attempt(request_id),
+            Next::LockOut(lockout) => {
+                log!("[listener] Too many failed attempts; locked out for {lockout:?}");
+                self.send(
+                    request_id,
+                    UiEvent::LockedOut {
+                        seconds: lockout.as_secs(),
+                    },
+                );
+                let weak = Rc::downgrade(self);
+                glib::timeout_add_local_once(lockout, move || {
+                    if let Some(shared) = weak.upgrade() {
+                        shared.next_attempt(request_id);
+                    }
+                });
+            }
+            Next::GiveUp if config.max_attempts == 1 => {
+                self.fail_request(request_id, "Authentication failed.")
+            }
+            Next::GiveUp => self.fail_request(
+                request_id,
+                &format!(
+                    "Authentication failed {} times in a row.",
+                    config.max_attempts
+                ),
+            ),
         }
     }
+
+    /// Start the next attempt for the selected identity, ending any lockout.
+    fn next_attempt(self: &Rc<Self>, request_id: u64) {
+        let _scope = crate::logging::request_scope(request_id);
+        let (identity, cookie) = {
+            let inner = self.inner.borrow();
+            let Some(active) = inner
+                .active
+                .as_ref()
+                .filter(|active| active.request_id == request_id)
+            else {
+                return;
+            };
+            let Some(choice) = active.choices.get(active.selected_user) else {
+                return;
+            };
+            (choice.identity.clone(), active.cookie.clone())
+        };
+        self.start_session(request_id, &identity, &cookie);
+    }
+
+    /// End `active` without authenticating, answering polkitd with `error`.
+    fn abort_request(&self, active: ActiveRequest, error: glib::Error) {
+        let _scope = crate::logging::request_scope(active.request_id);
+        if error.matches(polkit::Error::Cancelled) {
+            self.control
+                .authentication_cancelled(&active.action_id, active.user());
+        }
+        self.control
+            .authentication_finished(&active.action_id, active.user(), false);
+        if let Some(session) = &active.session {
+            session.cancel();
+        }
+        unsafe { active.task.return_result(Err(error)) };
+        self.send(active.request_id, UiEvent::AuthComplete { success: false });
+    }
+}
+
+/// Substitute each `{name}` in `template` with its value in one pass, so
+/// text inside an inserted value is never expanded again. Unknown names are
+/// left as they are.
+fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let name = &rest[1..end];
+            let (_, value) = values.iter().find(|(key, _)| *key == name)?;
+            Some((value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                filled.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+fn details_map(details: &polkit::Details) -> BTreeMap<String, String> {
+    details
+        .keys()
+        .into_iter()
+        .filter_map(|key| {
+            let value = details.lookup(&key)?;
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Why a request was rejected before any session was started.
+#[derive(Debug)]
+enum RequestError {
+    MissingActionId,
+    MissingCookie,
+    NoIdentities,
+    InvalidIdentities { count: usize },
+}
+
+impl RequestError {
+    fn name(&self) -> &'static str {
+        match self {
+            RequestError::MissingActionId => "MissingActionId",
+            RequestError::MissingCookie => "MissingCookie",
+            RequestError::NoIdentities => "NoIdentities",
+            RequestError::InvalidIdentities { .. } => "InvalidIdentities",
+        }
+    }
+
+    /// Malformed arguments map to `org.freedesktop.DBus.Error.InvalidArgs`;
+    /// requests we merely can't serve to `PolicyKit1.Error.Failed`.
+    fn into_glib_error(self, request_id: u64) -> glib::Error {
+        let message = format!("request #{request_id}: {self}");
+        match self {
+            RequestError::MissingActionId | RequestError::MissingCookie => {
+                glib::Error::new(gio::DBusError::InvalidArgs, &message)
+            }
+            RequestError::NoIdentities | RequestError::InvalidIdentities { .. } => {
+                glib::Error::new(polkit::Error::Failed, &message)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: ", self.name())?;
+        match self {
+            RequestError::MissingActionId => {
+                write!(
+                    f,
+                    "the request does not name the polkit action being authorized"
+                )
+            }
+            RequestError::MissingCookie => write!(
+                f,
+                "the request has no cookie, so a response could not be matched to it"
+            ),
+            RequestError::NoIdentities => write!(
+                f,
+                "no identities were offered; check the admin rules for this action"
+            ),
+            RequestError::InvalidIdentities { count } => write!(
+                f,
+                "none of the {count} offered identities is a resolvable unix user"
+            ),
+        }
+    }
+}
+
+/// Check the polkit arguments and resolve the identities we can authenticate as.
+fn validate_request(
+    request: &AuthRequest,
+    identities: Vec<polkit::Identity>,
+) -> Result<Vec<IdentityChoice>, RequestError> {
+    if request.action_id.is_empty() {
+        return Err(RequestError::MissingActionId);
+    }
+    if request.cookie.is_empty() {
+        return Err(RequestError::MissingCookie);
+    }
+    if identities.is_empty() {
+        return Err(RequestError::NoIdentities);
+    }
+
+    let count = identities.len();
+    let choices: Vec<IdentityChoice> = identities
+        .into_iter()
+        .filter_map(|identity| {
+            identity
+                .downcast_ref::<polkit::UnixUser>()
+                .and_then(|user| user.name())
+                .map(|user| IdentityChoice {
+                    user: user.to_string(),
+                    identity,
+                })
+        })
+        .collect();
+
+    if choices.is_empty() {
+        return Err(RequestError::InvalidIdentities { count });
+    }
+    Ok(choices)
+}
+
+/// The shared state, if `attempt_id` is still the live attempt of `request_id`.
+fn active_attempt(
+    weak: &Weak<SharedState>,
+    request_id: u64,
+    attempt_id: u64,
+) -> Option<Rc<SharedState>> {
+    let shared = weak.upgrade()?;
+    let is_active = matches!(
+        shared.inner.borrow().active.as_ref(),
+        Some(active) if active.request_id == request_id
+            && active.conversation.attempt_id() == attempt_id
+    );
+    is_active.then_some(shared)
 }
 
-fn is_active_attempt(weak: &Weak<SharedState>, request_id: u64, attempt_id: u64) -> bool {
-    let Some(shared) = weak.upgrade() else {
-        return false;
+/// Cancel a request that was never shown, so there is nothing to tell the
+/// UI or the bus about.
+fn drop_pending(pending: PendingRequest) {
+    let _scope = crate::logging::request_scope(pending.request_id);
+    unsafe {
+        pending
+            .task
+            .return_result(Err(cancelled_error(pending.request_id)))
     };
+}
 
-    let inner = shared.inner.borrow();
-    matches!(
-        inner.active.as_ref(),
-        Some(active) if active.request_id == request_id && active.attempt_id == attempt_id
+/// Errors use polkit's own domain, which GDBus maps to the
+/// `org.freedesktop.PolicyKit1.Error.*` names polkitd and pkexec expect.
+fn cancelled_error(request_id: u64) -> glib::Error {
+    glib::Error::new(
+        polkit::Error::Cancelled,
+        &format!("request #{request_id}: Authentication cancelled"),
     )
 }
 
-fn auth_failed_error() -> glib::Error {
-    glib::Error::new(glib::FileError::Failed, "Authentication failed")
+fn failed_error(request_id: u64, reason: &str) -> glib::Error {
+    glib::Error::new(
+        polkit::Error::Failed,
+        &format!("request #{request_id}: {reason}"),
+    )
 }
 
-fn cancelled_error() -> glib::Error {
-    glib::Error::new(gio::IOErrorEnum::Cancelled, "Authentication cancelled")
+fn duplicate_error(request_id: u64, existing: u64) -> glib::Error {
+    glib::Error::new(
+        polkit::Error::Failed,
+        &format!("request #{request_id}: request #{existing} already uses this cookie"),
+    )
+}
+
+fn denied_error(request_id: u64, action_id: &str) -> glib::Error {
+    glib::Error::new(
+        polkit::Error::NotAuthorized,
+        &format!("request #{request_id}: {action_id} is denied by badged's configuration"),
+    )
+}
+
+fn rate_limited_error(request_id: u64, caller: &str) -> glib::Error {
+    glib::Error::new(
+        polkit::Error::NotAuthorized,
+        &format!("request #{request_id}: too many authentication requests from {caller}"),
+    )
 }
 
 // --- GObject subclass ---
@@ -308,6 +1355,13 @@ impl ObjectSubclass for BadgedListenerPriv {
     const NAME: &'static str = "BadgedListener";
     type Type = BadgedListener;
     type ParentType = polkit_agent_rs::Listener;
+
+    fn class_init(klass: &mut Self::Class) {
+        // polkit-agent-rs's trampoline drops the task's error and leaves the
+        // GError unset, so libpolkit-agent has nothing to reply with.
+        let klass = klass as *mut Self::Class as *mut polkit_agent_rs_sys::PolkitAgentListenerClass;
+        unsafe { (*klass).initiate_authentication_finish = Some(initiate_authentication_finish) };
+    }
 }
 
 impl ObjectImpl for BadgedListenerPriv {}
@@ -317,37 +1371,90 @@ impl ListenerImpl for BadgedListenerPriv {
 
     fn initiate_authentication(
         &self,
-        _action_id: &str,
+        action_id: &str,
         message: &str,
-        _icon_name: &str,
-        _details: &polkit::Details,
+        icon_name: &str,
+        details: &polkit::Details,
         cookie: &str,
         identities: Vec<polkit::Identity>,
         cancellable: gio::Cancellable,
         task: gio::Task<bool>,
     ) {
-        eprintln!("[listener] initiate_authentication");
+        log!("[listener] initiate_authentication");
 
         if let Some(shared) = self.shared.borrow().clone() {
-            shared.start_request(message, cookie, identities, task, cancellable);
+            let request = AuthRequest {
+                action_id: action_id.to_owned(),
+                message: message.to_owned(),
+                icon_name: icon_name.to_owned(),
+                cookie: cookie.to_owned(),
+                details: details_map(details),
+            };
+            shared.start_request(request, identities, task, cancellable);
         } else {
             unsafe {
                 task.return_result(Err(glib::Error::new(
-                    glib::FileError::Failed,
+                    polkit::Error::Failed,
                     "Shared state unavailable",
                 )))
             };
         }
     }
 
+    /// Not called: `class_init` installs [`initiate_authentication_finish`]
+    /// in its place.
     fn initiate_authentication_finish(&self, result: Result<gio::Task<bool>, glib::Error>) -> bool {
-        match result {
-            Ok(task) => unsafe { task.propagate() }.unwrap_or(false),
-            Err(_) => false,
+        result.and_then(finish_task).is_ok()
+    }
+}
+
+/// The listener's `initiate_authentication_finish`. libpolkit-agent answers
+/// polkitd's `BeginAuthentication` with the error set here, which GDBus
+/// turns into the matching `org.freedesktop.PolicyKit1.Error.*` name.
+unsafe extern "C" fn initiate_authentication_finish(
+    _listener: *mut polkit_agent_rs_sys::PolkitAgentListener,
+    result: *mut gio::ffi::GAsyncResult,
+    error: *mut *mut glib::ffi::GError,
+) -> glib::ffi::gboolean {
+    let result: gio::AsyncResult = unsafe { from_glib_none(result) };
+    let finished = match result.downcast::<gio::Task<bool>>() {
+        Ok(task) => finish_task(task),
+        Err(_) => Err(glib::Error::new(
+            polkit::Error::Failed,
+            "Unexpected result for the authentication",
+        )),
+    };
+    match finished {
+        Ok(()) => true.into_glib(),
+        Err(err) => {
+            if !error.is_null() {
+                unsafe { *error = err.into_glib_ptr() };
+            }
+            false.into_glib()
         }
     }
 }
 
+/// The outcome `task` was completed with, logged.
+fn finish_task(task: gio::Task<bool>) -> Result<(), glib::Error> {
+    let err = match unsafe { task.propagate() } {
+        Ok(true) => return Ok(()),
+        Ok(false) => glib::Error::new(polkit::Error::Failed, "Authentication failed"),
+        Err(err) => err,
+    };
+    let name = if err.matches(polkit::Error::Cancelled) {
+        "Cancelled"
+    } else if err.matches(polkit::Error::NotAuthorized) {
+        "NotAuthorized"
+    } else if err.matches(gio::DBusError::InvalidArgs) {
+        "InvalidArgs"
+    } else {
+        "Failed"
+    };
+    log!("[listener] Authentication ended with {name}: {err}");
+    Err(err)
+}
+
 // --- Public GObject wrapper ---
 
 glib::wrapper! {
@@ -364,18 +1471,78 @@ impl BadgedListener {
 
     /// Register as a polkit agent for the current process's session.
     /// Returns a handle that unregisters on drop — keep it alive for the process lifetime.
-    pub fn register_for_current_session(&self) -> Result<impl Drop, glib::Error> {
-        let subject = polkit::UnixSession::new_for_process_sync(
-            std::process::id() as i32,
-            None::<&gio::Cancellable>,
-        )
-        .expect("Failed to resolve session for current process");
+    ///
+    /// A `fallback` agent is only used by polkitd while no regular agent is
+    /// registered for the session.
+    pub fn register_for_current_session(&self, fallback: bool) -> Result<impl Drop, glib::Error> {
+        let pid = std::process::id() as i32;
+        let subject: polkit::Subject =
+            match polkit::UnixSession::new_for_process_sync(pid, None::<&gio::Cancellable>) {
+                Ok(session) => session.upcast(),
+                Err(err) => {
+                    // No logind session (some compositors, systemd user
+                    // services): act as the agent for this process instead.
+                    log!(
+                        "[listener] No session for pid {pid} ({err}), registering for the process"
+                    );
+                    let start_time = process_start_time().ok_or_else(|| {
+                        glib::Error::new(polkit::Error::Failed, "cannot read /proc/self/stat")
+                    })?;
+                    // A uid of -1 lets polkit look it up from /proc.
+                    polkit::UnixProcess::new_for_owner(pid, start_time, -1).upcast()
+                }
+            };
+
+        let options = glib::VariantDict::new(None);
+        if fallback {
+            options.insert("fallback", true);
+        }
 
-        self.register(
+        self.register_with_options(
             RegisterFlags::NONE,
             &subject,
             "/org/freedesktop/PolicyKit1/AuthenticationAgent",
+            Some(&options.end()),
             None::<&gio::Cancellable>,
         )
     }
 }
+
+/// Start time of this process in clock ticks since boot (field 22 of
+/// `/proc/self/stat`), which polkit uses to tell reused pids apart.
+fn process_start_time() -> Option<u64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name may contain spaces; fields resume after its `)`.
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fill_template;
+
+    #[test]
+    fn fills_each_placeholder() {
+        let values = [("program", "gparted"), ("user", "alice")];
+        assert_eq!(
+            fill_template("{program} wants {user}", &values),
+            "gparted wants alice"
+        );
+    }
+
+    #[test]
+    fn leaves_placeholders_inside_values_alone() {
+        let values = [("message", "{user} {device}"), ("user", "alice")];
+        assert_eq!(
+            fill_template("{message} as {user}", &values),
+            "{user} {device} as alice"
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_and_unterminated_braces() {
+        let values = [("user", "alice")];
+        assert_eq!(fill_template("{nope} {user", &values), "{nope} {user");
+        assert_eq!(fill_template("{{user}}", &values), "{alice}");
+    }
+}
@@ -0,0 +1,230 @@
+//! Diagnostic logging to stderr (journald under systemd) and an optional log file.
+//!
+//! The log file is rotated by size and every line is passed through secret
+//! redaction before it is written anywhere.
+
+use std::cell::Cell;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use gtk4::glib;
+
+use crate::secret::SecretString;
+
+/// Rotate the log file once it grows past this many bytes.
+const MAX_LOG_SIZE: u64 = 1024 * 1024;
+
+/// Number of rotated files (`<path>.1` ... `<path>.N`) to keep.
+const MAX_ROTATED_FILES: u32 = 3;
+
+const REDACTED: &str = "<redacted>";
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+static SECRETS: Mutex<Vec<(u64, SecretString)>> = Mutex::new(Vec::new());
+static NEXT_SECRET: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The request the code running on this thread is handling, if any.
+    static REQUEST: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Log a line to stderr and, if configured, to the log file.
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::logging::write_line(&format!($($arg)*))
+    };
+}
+
+/// Start mirroring log lines into `path`, appending to any existing file.
+pub fn init_file(path: &Path) -> std::io::Result<()> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)?;
+    }
+    let log = LogFile::open(path)?;
+    *LOG_FILE.lock().unwrap_or_else(|e| e.into_inner()) = Some(log);
+    Ok(())
+}
+
+/// The file given to [`init_file`], if any.
+pub fn file_path() -> Option<PathBuf> {
+    let guard = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner());
+    guard.as_ref().map(|log| log.path.clone())
+}
+
+/// Never log `secret` verbatim while the returned guard is alive. The
+/// copy kept for matching is wiped once the guard is dropped.
+pub fn register_secret(secret: &str) -> Redaction {
+    let id = NEXT_SECRET.fetch_add(1, Ordering::Relaxed);
+    if !secret.is_empty() {
+        SECRETS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((id, SecretString::from(secret.to_owned())));
+    }
+    Redaction(id)
+}
+
+/// Keeps a secret registered with [`register_secret`] redacted.
+pub struct Redaction(u64);
+
+impl Drop for Redaction {
+    fn drop(&mut self) {
+        let mut secrets = SECRETS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(pos) = secrets.iter().position(|(id, _)| *id == self.0) {
+            secrets.swap_remove(pos);
+        }
+    }
+}
+
+/// Tag lines logged on this thread with `request_id` until the returned
+/// guard is dropped, then go back to the previous tag.
+///
+/// Taken at the top of each call and callback that handles one request, so
+/// nothing logged outside it is tagged. The tag is the same `#N` the request
+/// carries in UI events, JSON events and error replies, so one request can
+/// be followed through the log.
+pub fn request_scope(request_id: u64) -> RequestScope {
+    RequestScope(REQUEST.replace(Some(request_id)))
+}
+
+pub struct RequestScope(Option<u64>);
+
+impl Drop for RequestScope {
+    fn drop(&mut self) {
+        REQUEST.set(self.0);
+    }
+}
+
+/// Replace every registered secret in `line` with a placeholder.
+pub fn redact(line: &str) -> String {
+    let secrets = SECRETS.lock().unwrap_or_else(|e| e.into_inner());
+    secrets.iter().fold(line.to_owned(), |line, (_, secret)| {
+        line.replace(secret.expose(), REDACTED)
+    })
+}
+
+#[doc(hidden)]
+pub fn write_line(line: &str) {
+    let line = match REQUEST.get() {
+        Some(request_id) => redact(&format!("[#{request_id}] {line}")),
+        None => redact(line),
+    };
+    eprintln!("{line}");
+
+    let mut guard = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(log) = guard.as_mut() else {
+        return;
+    };
+
+    let timestamp = glib::DateTime::now_local()
+        .and_then(|now| now.format("%F %T"))
+        .map(|ts| ts.to_string())
+        .unwrap_or_default();
+    let entry = format!("{timestamp} {line}\n");
+
+    if let Err(err) = log.append(&entry) {
+        eprintln!("[log] Failed to rotate {}: {err}", log.path.display());
+        *guard = None;
+    }
+}
+
+impl LogFile {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            size,
+        })
+    }
+
+    /// Append `entry`, rotating first if it would take the file past
+    /// `MAX_LOG_SIZE`. Only a failed rotation is an error; a failed write
+    /// just loses the line.
+    fn append(&mut self, entry: &str) -> std::io::Result<()> {
+        if self.size + entry.len() as u64 > MAX_LOG_SIZE {
+            self.rotate()?;
+        }
+        if self.file.write_all(entry.as_bytes()).is_ok() {
+            self.size += entry.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for n in (1..MAX_ROTATED_FILES).rev() {
+            let from = rotated_path(&self.path, n);
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, n + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        *self = Self::open(&self.path)?;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_registered_secrets_until_dropped() {
+        let redaction = register_secret("cookie-5f2a9e");
+        assert_eq!(redact("cancel cookie-5f2a9e now"), "cancel <redacted> now");
+        drop(redaction);
+        assert_eq!(
+            redact("cancel cookie-5f2a9e now"),
+            "cancel cookie-5f2a9e now"
+        );
+    }
+
+    #[test]
+    fn empty_secrets_redact_nothing() {
+        let _redaction = register_secret("");
+        assert_eq!(redact("nothing to hide"), "nothing to hide");
+    }
+
+    #[test]
+    fn rotates_once_the_size_limit_would_be_exceeded() {
+        let dir = std::env::temp_dir().join(format!("badged-log-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("badged.log");
+        let mut log = LogFile::open(&path).unwrap();
+
+        let half = "x".repeat(MAX_LOG_SIZE as usize / 2);
+        log.append(&half).unwrap();
+        log.append(&half).unwrap();
+        assert_eq!(log.size, MAX_LOG_SIZE);
+        assert!(!rotated_path(&path, 1).exists());
+
+        log.append("one more\n").unwrap();
+        assert_eq!(log.size, "one more\n".len() as u64);
+        assert_eq!(
+            fs::metadata(rotated_path(&path, 1)).unwrap().len(),
+            MAX_LOG_SIZE
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one more\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
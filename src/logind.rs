@@ -0,0 +1,91 @@
+//! Whether our login session is in the foreground.
+//!
+//! With fast user switching the display badged prompts on may belong to a
+//! session nobody is looking at. This follows logind's `Active` property for
+//! our session so requests can wait until the user switches back.
+
+use std::rc::Rc;
+
+use gtk4::gio;
+use gtk4::glib;
+use gtk4::prelude::*;
+
+use crate::listener::SharedState;
+
+const LOGIND_NAME: &str = "org.freedesktop.login1";
+const MANAGER_PATH: &str = "/org/freedesktop/login1";
+const MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
+const SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+
+/// Keeps the property subscription alive; dropping it stops following.
+pub struct Watch {
+    _proxy: Option<gio::DBusProxy>,
+}
+
+/// Report our session's `Active` property to `shared`, now and on every
+/// change. Without logind, or outside a session, the session counts as
+/// always active.
+pub fn watch(shared: Rc<SharedState>) -> Watch {
+    let proxy = match session_proxy() {
+        Ok(proxy) => proxy,
+        Err(err) => {
+            log!("[logind] Not following session activity: {err}");
+            return Watch { _proxy: None };
+        }
+    };
+
+    if let Some(active) = is_active(&proxy) {
+        shared.set_session_active(active);
+    }
+    let weak = Rc::downgrade(&shared);
+    // gio-rs has no typed binding for this signal.
+    proxy.connect_local("g-properties-changed", false, move |values| {
+        let proxy = values[0].get::<gio::DBusProxy>().ok()?;
+        if let (Some(shared), Some(active)) = (weak.upgrade(), is_active(&proxy)) {
+            shared.set_session_active(active);
+        }
+        None
+    });
+
+    Watch {
+        _proxy: Some(proxy),
+    }
+}
+
+fn is_active(proxy: &gio::DBusProxy) -> Option<bool> {
+    proxy.cached_property("Active")?.get::<bool>()
+}
+
+/// Proxy for our session object: `$XDG_SESSION_ID` if set, otherwise the
+/// session our process belongs to.
+fn session_proxy() -> Result<gio::DBusProxy, glib::Error> {
+    let bus = gio::bus_get_sync(gio::BusType::System, gio::Cancellable::NONE)?;
+    let (method, args) = match std::env::var("XDG_SESSION_ID") {
+        Ok(id) if !id.is_empty() => ("GetSession", (id,).to_variant()),
+        _ => ("GetSessionByPID", (std::process::id(),).to_variant()),
+    };
+    let reply = bus.call_sync(
+        Some(LOGIND_NAME),
+        MANAGER_PATH,
+        MANAGER_INTERFACE,
+        method,
+        Some(&args),
+        Some(glib::VariantTy::new("(o)").expect("valid type string")),
+        gio::DBusCallFlags::NONE,
+        -1,
+        gio::Cancellable::NONE,
+    )?;
+    let (path,) = reply
+        .get::<(glib::variant::ObjectPath,)>()
+        .expect("reply type checked by D-Bus");
+
+    gio::DBusProxy::new_sync(
+        &bus,
+        gio::DBusProxyFlags::DO_NOT_CONNECT_SIGNALS,
+        None,
+        Some(LOGIND_NAME),
+        path.as_str(),
+        SESSION_INTERFACE,
+        gio::Cancellable::NONE,
+    )
+}
@@ -1,24 +1,261 @@
 //! Polkit authentication agent with GTK4.
 
+#[macro_use]
+mod logging;
+mod appearance;
+mod auth;
+mod caller;
+mod cli;
+mod config;
+mod control;
+mod details;
+mod dialog;
+mod doctor;
+mod events;
 mod listener;
+mod logind;
+mod pam;
+mod polkitd;
+mod prompt;
+mod report;
+mod secret;
+mod setup;
+mod stats;
+mod theme;
+mod transcript;
 mod ui;
 
+use std::any::Any;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use gtk4::{gio, glib};
+
+use cli::Command;
+use config::Config;
+use control::Control;
 use listener::{BadgedListener, SharedState};
 use ui::UiChannels;
 
+/// How long to keep retrying GTK initialization before giving up.
+const GTK_INIT_TIMEOUT: Duration = Duration::from_secs(120);
+const GTK_INIT_MAX_DELAY: Duration = Duration::from_secs(5);
+/// How long `--replace` waits for the previous agent to unregister.
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to keep retrying registration while polkitd is unavailable.
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(60);
+const REGISTER_MAX_DELAY: Duration = Duration::from_secs(5);
+
 fn main() {
-    gtk4::init().expect("Failed to initialize GTK4");
+    let options = match Command::from_env() {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Status { actions }) => {
+            if actions {
+                stats::print_actions();
+            } else {
+                stats::print_summary();
+            }
+            return;
+        }
+        Ok(Command::Doctor) => {
+            let healthy = doctor::print_report();
+            std::process::exit(if healthy { 0 } else { 1 });
+        }
+        Ok(Command::Report { output, log_file }) => {
+            match report::create(&output, log_file.as_deref()) {
+                Ok(path) => {
+                    println!("Wrote {}", path.display());
+                    return;
+                }
+                Err(err) => {
+                    eprintln!("badged: cannot create report: {err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Ok(Command::Help) => {
+            println!("{}", cli::usage());
+            return;
+        }
+        Ok(Command::Version) => {
+            println!("badged {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(err) => {
+            eprintln!("badged: {err}\n\n{}", cli::usage());
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(path) = &options.log_file {
+        if let Err(err) = logging::init_file(path) {
+            eprintln!("badged: cannot open log file {}: {err}", path.display());
+            std::process::exit(1);
+        }
+    }
+
+    // Before any thread exists, so changing the environment is sound.
+    negotiate_locale();
 
-    let (event_tx, event_rx) = std::sync::mpsc::channel();
-    let shared = SharedState::new(event_tx);
+    let config = Rc::new(Config::load());
 
-    // Create and register the polkit listener.
-    let agent_listener = BadgedListener::new(shared.clone());
-    let _handler = agent_listener
-        .register_for_current_session()
-        .expect("Failed to register polkit agent");
-    eprintln!("[main] Polkit agent registered");
+    let helper = doctor::check_helper();
+    match helper.status {
+        doctor::Status::Error => {
+            log!("[main] {helper}; authentication will fail until polkit is installed correctly")
+        }
+        doctor::Status::Warning => log!("[main] {helper}"),
+        doctor::Status::Ok => {}
+    }
+
+    // Register with polkit only once there is a display to prompt on.
+    if let Err(err) = init_gtk() {
+        log!("[main] Giving up on GTK4 initialization: {err}");
+        std::process::exit(1);
+    }
+
+    let (event_tx, event_rx) = async_channel::unbounded();
+    let control = Control::new();
+    let shared = SharedState::new(
+        event_tx,
+        options.json_events,
+        control.clone(),
+        config.clone(),
+    );
+    control.attach(&shared);
+
+    // Registration is deferred to the primary instance's startup, so a second
+    // `badged` never competes with the running agent.
+    let agent_shared = shared.clone();
+    let bus_name = config.bus_name.clone();
+    let start_agent = move || {
+        // Create and register the polkit listener.
+        // Know polkitd's bus name before it can call the agent.
+        let polkitd = polkitd::watch();
+        let logind = logind::watch(agent_shared.clone());
+        // Only one badged per session: claim the name before polkit.
+        let bus_name = control.own_name(&bus_name, options.replace)?;
+        let listener = BadgedListener::new(agent_shared.clone());
+        let handler = register_agent(&listener, options.fallback, options.replace)?;
+        log!("[main] Polkit agent registered");
+        control.set_registered(true);
+
+        Ok(AgentRegistration {
+            shared: agent_shared,
+            control: control.clone(),
+            _listener: listener,
+            _handler: Box::new(handler),
+            bus_name: Some(bus_name),
+            _polkitd: polkitd,
+            _logind: logind,
+        })
+    };
 
     // Run the GTK4 UI (blocks until app exits).
-    ui::run(UiChannels { event_rx, shared });
+    ui::run(
+        config,
+        UiChannels { event_rx, shared },
+        options.replace,
+        start_agent,
+    );
+}
+
+/// Everything that keeps the agent registered; dropped on shutdown.
+struct AgentRegistration {
+    shared: Rc<SharedState>,
+    control: Rc<Control>,
+    _listener: BadgedListener,
+    _handler: Box<dyn Any>,
+    bus_name: Option<gio::OwnerId>,
+    _polkitd: polkitd::Watch,
+    _logind: logind::Watch,
+}
+
+impl Drop for AgentRegistration {
+    fn drop(&mut self) {
+        log!("[main] Unregistering polkit agent");
+        self.shared.cancel_all();
+        // GTask may defer the Cancelled replies to an idle callback; run
+        // those and push them onto the bus before the listener is
+        // unregistered and the process exits, or polkitd never hears back.
+        let context = glib::MainContext::default();
+        while context.iteration(false) {}
+        if let Ok(bus) = gio::bus_get_sync(gio::BusType::System, gio::Cancellable::NONE) {
+            if let Err(err) = bus.flush_sync(gio::Cancellable::NONE) {
+                log!("[main] Failed to flush replies to polkitd: {err}");
+            }
+        }
+        self.control.set_registered(false);
+        if let Some(id) = self.bus_name.take() {
+            gio::bus_unown_name(id);
+        }
+    }
+}
+
+/// Register `listener` with polkit. With `replace`, the instance being
+/// replaced may still hold the session's agent slot for a moment, so keep
+/// trying for a little while before giving up. Other failures (polkitd not
+/// up yet early in session startup) are retried with backoff.
+fn register_agent(
+    listener: &BadgedListener,
+    fallback: bool,
+    replace: bool,
+) -> Result<impl Drop, String> {
+    let started = Instant::now();
+    let mut delay = Duration::from_millis(250);
+    loop {
+        match listener.register_for_current_session(fallback) {
+            Ok(handler) => return Ok(handler),
+            Err(err) if err.message().contains("already exists") => {
+                if replace && started.elapsed() < REPLACE_TIMEOUT {
+                    std::thread::sleep(Duration::from_millis(200));
+                    continue;
+                }
+                return Err(format!(
+                    "Another authentication agent is already registered for this session ({err}). \
+                     Stop it first, run `badged --replace` if it is badged, or use --fallback."
+                ));
+            }
+            Err(err) if started.elapsed() < REGISTER_TIMEOUT => {
+                log!("[main] Polkit registration failed ({err}), retrying in {delay:?}");
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(REGISTER_MAX_DELAY);
+            }
+            Err(err) => return Err(format!("Failed to register polkit agent: {err}")),
+        }
+    }
+}
+
+/// libpolkit-agent registers with polkitd using `$LANG` alone. Resolve the
+/// message locale with the usual POSIX precedence (`LC_ALL`, then
+/// `LC_MESSAGES`, then `LANG`) and export it as `LANG`, so polkitd's
+/// messages arrive localized. PAM's prompts aren't affected: the helper
+/// clears its environment before starting PAM.
+fn negotiate_locale() {
+    let locale = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|value| !value.is_empty()))
+        .unwrap_or_else(|| "C".to_owned());
+    if std::env::var("LANG").ok().as_deref() != Some(locale.as_str()) {
+        log!("[main] Using locale {locale} for polkit messages");
+        std::env::set_var("LANG", &locale);
+    }
+}
+
+/// Initialize GTK4, retrying with backoff while no display is available
+/// (e.g. when autostarted before the compositor is up).
+fn init_gtk() -> Result<(), gtk4::glib::BoolError> {
+    let started = Instant::now();
+    let mut delay = Duration::from_millis(250);
+    loop {
+        match gtk4::init() {
+            Ok(()) => return Ok(()),
+            Err(err) if started.elapsed() < GTK_INIT_TIMEOUT => {
+                log!("[main] GTK4 initialization failed ({err}), retrying in {delay:?}");
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(GTK_INIT_MAX_DELAY);
+            }
+            Err(err) => return Err(err),
+        }
+    }
 }
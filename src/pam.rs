@@ -0,0 +1,367 @@
+//! Classification of PAM conversation messages.
+//!
+//! PAM modules only send free-form text, so the method behind a message is
+//! recognized from the wording used by the common modules.
+
+/// Authentication method a PAM message belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    Fingerprint,
+    SecurityKey,
+    OneTimeCode,
+    Smartcard,
+    Face,
+    Other,
+}
+
+impl AuthMethod {
+    pub fn classify(text: &str) -> Self {
+        let text = text.to_lowercase();
+        let mentions = |words: &[&str]| words.iter().any(|word| text.contains(word));
+
+        if mentions(&["finger", "swipe"]) {
+            AuthMethod::Fingerprint
+        } else if mentions(&[
+            "u2f",
+            "fido",
+            "security key",
+            "touch the device",
+            "touch your device",
+            "user presence",
+        ]) {
+            AuthMethod::SecurityKey
+        } else if mentions(&["verification code", "one-time", "otp", "token code"]) {
+            AuthMethod::OneTimeCode
+        } else if mentions(&[
+            "smart card",
+            "smartcard",
+            "insert card",
+            "insert your card",
+            "card reader",
+            "card removed",
+            "pin for",
+        ]) {
+            AuthMethod::Smartcard
+        } else if ["face", "facial", "howdy", "ir camera", "too dark"]
+            .iter()
+            .any(|phrase| mentions_word(&text, phrase))
+        {
+            AuthMethod::Face
+        } else {
+            AuthMethod::Other
+        }
+    }
+
+    /// Like `classify`, but while Howdy is known to be running its bare
+    /// failure lines ("Failure, timeout reached") count as face unlock too;
+    /// any other module may word its failures the same way.
+    pub fn classify_during(text: &str, face_active: bool) -> Self {
+        match Self::classify(text) {
+            AuthMethod::Other
+                if face_active && text.trim_start().to_lowercase().starts_with("failure, ") =>
+            {
+                AuthMethod::Face
+            }
+            method => method,
+        }
+    }
+
+    /// Whether the message reports a problem the user can fix without the
+    /// attempt counting as a failed one, like pulling the smartcard.
+    pub fn is_recoverable(text: &str) -> bool {
+        let text = text.to_lowercase();
+        Self::classify(&text) == AuthMethod::Smartcard
+            && ["removed", "not present", "no card", "insert"]
+                .iter()
+                .any(|word| text.contains(word))
+    }
+
+    /// Glyph for the dialog's status icon while this method is active.
+    pub fn icon(self) -> Option<&'static str> {
+        match self {
+            AuthMethod::Fingerprint => Some("👆"),
+            AuthMethod::SecurityKey => Some("🔑"),
+            AuthMethod::OneTimeCode => Some("🔢"),
+            AuthMethod::Smartcard => Some("💳"),
+            AuthMethod::Face => Some("📷"),
+            AuthMethod::Other => None,
+        }
+    }
+}
+
+/// Whether `phrase` occurs in `text` as whole words, so "face" doesn't
+/// match "interface".
+fn mentions_word(text: &str, phrase: &str) -> bool {
+    text.match_indices(phrase).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + phrase.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+/// Whether a PAM error message reports a passing condition, worth showing
+/// only briefly: a fingerprint that didn't match or a scan that timed out.
+pub fn is_transient_error(text: &str) -> bool {
+    match FingerprintState::parse(text) {
+        Some(state) => state != FingerprintState::Unavailable,
+        None => {
+            let text = text.to_lowercase();
+            ["timed out", "timeout", "try again"]
+                .iter()
+                .any(|word| text.contains(word))
+        }
+    }
+}
+
+/// Whether a PAM prompt asks for a PIN (pam_pkcs11, pam_u2f, ...).
+pub fn is_pin_prompt(prompt: &str) -> bool {
+    prompt
+        .split(|c: char| !c.is_alphanumeric())
+        .any(|word| word.eq_ignore_ascii_case("pin"))
+}
+
+/// How many tries pam_fprintd allows per conversation unless configured
+/// otherwise (its `max-tries` option).
+pub const FPRINTD_MAX_TRIES: u32 = 3;
+
+/// How long pam_fprintd waits for a finger on each try unless configured
+/// otherwise (its `timeout` option).
+pub const FPRINTD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Where pam_fprintd is in its conversation, parsed from its fixed message
+/// set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FingerprintState {
+    /// "Place your finger on ..." or "Swipe your finger across ...".
+    Waiting,
+    /// The scan was unusable ("Swipe was too short", "not centered", "again").
+    Retry,
+    /// "Failed to match fingerprint".
+    NoMatch,
+    /// "Verification timed out".
+    TimedOut,
+    /// The reader could not be used at all.
+    Unavailable,
+}
+
+impl FingerprintState {
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.to_lowercase();
+        let mentions = |words: &[&str]| words.iter().any(|word| text.contains(word));
+
+        if mentions(&["failed to match", "not recognized", "no match"]) {
+            Some(FingerprintState::NoMatch)
+        } else if mentions(&["timed out", "timeout"]) {
+            Some(FingerprintState::TimedOut)
+        } else if mentions(&[
+            "too short",
+            "not centered",
+            "remove your finger",
+            "finger again",
+            "on the reader again",
+        ]) {
+            Some(FingerprintState::Retry)
+        } else if mentions(&["place your", "swipe your"]) && mentions(&["finger"]) {
+            Some(FingerprintState::Waiting)
+        } else if mentions(&["fingerprint", "finger"])
+            && mentions(&["unknown error", "could not", "failed to open", "no device"])
+        {
+            Some(FingerprintState::Unavailable)
+        } else {
+            None
+        }
+    }
+
+    pub fn icon(self) -> &'static str {
+        match self {
+            FingerprintState::Waiting | FingerprintState::Retry => "👆",
+            FingerprintState::NoMatch => "❌",
+            FingerprintState::TimedOut => "⏱",
+            FingerprintState::Unavailable => "⚠️",
+        }
+    }
+
+    /// Whether the message reports a failure rather than an instruction.
+    pub fn is_error(self) -> bool {
+        matches!(
+            self,
+            FingerprintState::NoMatch | FingerprintState::TimedOut | FingerprintState::Unavailable
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn classifies_common_module_messages() {
+        let cases = [
+            (
+                "Place your finger on the fingerprint reader",
+                AuthMethod::Fingerprint,
+            ),
+            (
+                "Swipe your right index finger across the reader",
+                AuthMethod::Fingerprint,
+            ),
+            ("Please touch the device.", AuthMethod::SecurityKey),
+            (
+                "Insert your U2F device, then press ENTER.",
+                AuthMethod::SecurityKey,
+            ),
+            ("Verification code: ", AuthMethod::OneTimeCode),
+            ("Please insert your smart card", AuthMethod::Smartcard),
+            ("PIN for Jose's card: ", AuthMethod::Smartcard),
+            ("Identifying face...", AuthMethod::Face),
+            ("Password: ", AuthMethod::Other),
+        ];
+        for (text, method) in cases {
+            assert_eq!(AuthMethod::classify(text), method, "{text}");
+        }
+    }
+
+    #[test]
+    fn classifies_case_insensitively() {
+        assert_eq!(
+            AuthMethod::classify("SECURITY KEY"),
+            AuthMethod::SecurityKey
+        );
+    }
+
+    #[test]
+    fn parses_pam_fprintd_messages() {
+        let cases = [
+            (
+                "Place your finger on the fingerprint reader",
+                Some(FingerprintState::Waiting),
+            ),
+            (
+                "Swipe your finger across the reader",
+                Some(FingerprintState::Waiting),
+            ),
+            (
+                "Swipe was too short, try again",
+                Some(FingerprintState::Retry),
+            ),
+            (
+                "Your finger was not centered, try swiping your finger again",
+                Some(FingerprintState::Retry),
+            ),
+            (
+                "Remove your finger, and try swiping your finger again",
+                Some(FingerprintState::Retry),
+            ),
+            (
+                "Failed to match fingerprint",
+                Some(FingerprintState::NoMatch),
+            ),
+            ("Verification timed out", Some(FingerprintState::TimedOut)),
+            (
+                "An unknown error occurred with the fingerprint reader",
+                Some(FingerprintState::Unavailable),
+            ),
+            ("Password: ", None),
+            ("Please touch the device.", None),
+        ];
+        for (text, state) in cases {
+            assert_eq!(FingerprintState::parse(text), state, "{text}");
+        }
+    }
+
+    #[test]
+    fn recognizes_pin_prompts() {
+        assert!(is_pin_prompt("PIN for Jose's card: "));
+        assert!(is_pin_prompt("Enter PIN:"));
+        assert!(is_pin_prompt("Smartcard pin"));
+        assert!(!is_pin_prompt("Password: "));
+        assert!(!is_pin_prompt("Spinning up the disk"));
+        assert!(!is_pin_prompt("Pinentry"));
+    }
+
+    #[test]
+    fn transient_errors_pass_and_fatal_ones_stay() {
+        assert!(is_transient_error("Failed to match fingerprint"));
+        assert!(is_transient_error("Fingerprint verification timed out"));
+        assert!(is_transient_error("Swipe was too short, try again"));
+        assert!(is_transient_error("Wrong code, try again"));
+        assert!(!is_transient_error("Could not open the fingerprint device"));
+        assert!(!is_transient_error("Your account has expired"));
+        assert!(!is_transient_error(
+            "Authentication token manipulation error"
+        ));
+    }
+
+    #[test]
+    fn face_words_must_stand_alone() {
+        assert_eq!(
+            AuthMethod::classify("Face detection image too dark"),
+            AuthMethod::Face
+        );
+        assert_eq!(
+            AuthMethod::classify("Network interface is down"),
+            AuthMethod::Other
+        );
+        assert_eq!(
+            AuthMethod::classify("Wipe the surface of the sensor"),
+            AuthMethod::Other
+        );
+        assert_eq!(AuthMethod::classify("Defaced token"), AuthMethod::Other);
+    }
+
+    #[test]
+    fn bare_failures_are_howdy_only_while_it_runs() {
+        assert_eq!(
+            AuthMethod::classify_during("Failure, timeout reached", true),
+            AuthMethod::Face
+        );
+        assert_eq!(
+            AuthMethod::classify_during("Failure, timeout reached", false),
+            AuthMethod::Other
+        );
+        assert_eq!(
+            AuthMethod::classify("Failure, timeout reached"),
+            AuthMethod::Other
+        );
+        assert_eq!(
+            AuthMethod::classify_during("Failure, card removed", true),
+            AuthMethod::Smartcard
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn classifies_any_message(text in any::<String>(), face_active in any::<bool>()) {
+            let method = AuthMethod::classify_during(&text, face_active);
+            let _ = (method.icon(), AuthMethod::is_recoverable(&text), is_transient_error(&text));
+            let _ = (is_pin_prompt(&text), FingerprintState::parse(&text).map(FingerprintState::icon));
+        }
+
+        #[test]
+        fn ignores_case_and_line_endings(text in "[ -~]{0,200}") {
+            let method = AuthMethod::classify(&text);
+            prop_assert_eq!(AuthMethod::classify(&text.to_uppercase()), method);
+            prop_assert_eq!(AuthMethod::classify(&format!("{text}\r\n")), method);
+            prop_assert_eq!(
+                FingerprintState::parse(&format!("{}\r\n", text.to_uppercase())),
+                FingerprintState::parse(&text)
+            );
+        }
+
+        #[test]
+        fn finds_pin_between_any_separators(before in "[ -/:-@]{1,10}", after in "[ -/:-@]{0,10}") {
+            let prompt = format!("Enter{before}PIN{after}");
+            prop_assert!(is_pin_prompt(&prompt));
+        }
+
+        #[test]
+        fn bare_failures_tolerate_leading_whitespace(space in "[ \t\r\n]{0,4}") {
+            prop_assert_eq!(
+                AuthMethod::classify_during(&format!("{space}Failure, timeout reached\r\n"), true),
+                AuthMethod::Face
+            );
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! polkitd's presence on the system bus.
+//!
+//! Tracks which connection currently owns `org.freedesktop.PolicyKit1`, logs
+//! daemon restarts, and makes sure only that connection can drive the agent
+//! object libpolkit-agent exports for us.
+
+use std::sync::Mutex;
+
+use gtk4::prelude::*;
+use gtk4::{gio, glib};
+
+const DBUS_NAME: &str = "org.freedesktop.DBus";
+const DBUS_PATH: &str = "/org/freedesktop/DBus";
+const POLKITD_NAME: &str = "org.freedesktop.PolicyKit1";
+const AGENT_PATH: &str = "/org/freedesktop/PolicyKit1/AuthenticationAgent";
+const AGENT_INTERFACE: &str = "org.freedesktop.PolicyKit1.AuthenticationAgent";
+
+/// Keeps the name watch and the sender filter installed; both are removed on drop.
+pub struct Watch {
+    unwatch: Option<Box<dyn FnOnce()>>,
+    filter: Option<(gio::DBusConnection, gio::FilterId)>,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        if let Some(unwatch) = self.unwatch.take() {
+            unwatch();
+        }
+        if let Some((connection, filter)) = self.filter.take() {
+            connection.remove_filter(filter);
+        }
+    }
+}
+
+/// Start following polkitd. libpolkit-agent re-registers the listener by
+/// itself when the daemon comes back (package upgrade, crash); this makes
+/// that visible in the log.
+///
+/// Call this before registering the agent: polkitd's current owner is
+/// looked up here, so its first call is never mistaken for a stranger's.
+pub fn watch() -> Watch {
+    // polkitd is bus-activated, so it may not be running yet at startup;
+    // only changes after it was first seen are worth logging.
+    let seen = std::rc::Rc::new(std::cell::Cell::new(false));
+    let seen_vanished = seen.clone();
+    let id = gio::bus_watch_name(
+        gio::BusType::System,
+        POLKITD_NAME,
+        gio::BusNameWatcherFlags::NONE,
+        move |_, _, name_owner| {
+            if seen.replace(true) {
+                log!(
+                    "[polkitd] polkitd restarted as {name_owner}; the agent is being re-registered"
+                );
+            }
+        },
+        move |_, _| {
+            if seen_vanished.get() {
+                log!("[polkitd] polkitd left the system bus; waiting for it to come back");
+            }
+        },
+    );
+
+    let filter = match gio::bus_get_sync(gio::BusType::System, gio::Cancellable::NONE) {
+        Ok(connection) => {
+            // Unique name of polkitd's connection. The filter keeps it
+            // current from NameOwnerChanged on GDBus's worker thread, in
+            // order with polkitd's calls; the name watch above only hears
+            // about a restart later, on the main loop.
+            let owner = Mutex::new(name_owner(&connection));
+            let filter = connection.add_filter(move |connection, message, incoming| {
+                if !incoming {
+                    return Some(message.clone());
+                }
+                if let Some(new_owner) = owner_change(message) {
+                    *owner.lock().unwrap() = new_owner;
+                    return Some(message.clone());
+                }
+                if !is_agent_call(message) {
+                    return Some(message.clone());
+                }
+                let sender = message.sender();
+                let allowed =
+                    sender.is_some() && owner.lock().unwrap().as_deref() == sender.as_deref();
+                if allowed {
+                    return Some(message.clone());
+                }
+                log!(
+                    "[polkitd] Refusing {} from {}: only polkitd may call the agent",
+                    message.member().as_deref().unwrap_or("call"),
+                    sender.as_deref().unwrap_or("unknown sender")
+                );
+                if !message
+                    .flags()
+                    .contains(gio::DBusMessageFlags::NO_REPLY_EXPECTED)
+                {
+                    let reply = message.new_method_error_literal(
+                        "org.freedesktop.DBus.Error.AccessDenied",
+                        "Only polkitd may call the authentication agent",
+                    );
+                    let _ = connection.send_message(&reply, gio::DBusSendMessageFlags::NONE);
+                }
+                None
+            });
+            Some((connection, filter))
+        }
+        Err(err) => {
+            log!("[polkitd] Cannot filter agent calls: {err}");
+            None
+        }
+    };
+
+    Watch {
+        unwatch: Some(Box::new(move || gio::bus_unwatch_name(id))),
+        filter,
+    }
+}
+
+/// polkitd's unique name right now, or `None` while it isn't running.
+fn name_owner(connection: &gio::DBusConnection) -> Option<String> {
+    let reply = connection
+        .call_sync(
+            Some(DBUS_NAME),
+            DBUS_PATH,
+            DBUS_NAME,
+            "GetNameOwner",
+            Some(&(POLKITD_NAME,).to_variant()),
+            Some(glib::VariantTy::new("(s)").unwrap()),
+            gio::DBusCallFlags::NONE,
+            -1,
+            gio::Cancellable::NONE,
+        )
+        .ok()?;
+    reply.get::<(String,)>().map(|(owner,)| owner)
+}
+
+/// polkitd's new owner if `message` is the bus announcing one: `Some(None)`
+/// when it left the bus, `None` for any other message.
+fn owner_change(message: &gio::DBusMessage) -> Option<Option<String>> {
+    if message.message_type() != gio::DBusMessageType::Signal
+        || message.sender().as_deref() != Some(DBUS_NAME)
+        || message.member().as_deref() != Some("NameOwnerChanged")
+    {
+        return None;
+    }
+    let (name, _old, new) = message.body()?.get::<(String, String, String)>()?;
+    (name == POLKITD_NAME).then(|| Some(new).filter(|new| !new.is_empty()))
+}
+
+fn is_agent_call(message: &gio::DBusMessage) -> bool {
+    message.message_type() == gio::DBusMessageType::MethodCall
+        && message.path().as_deref() == Some(AGENT_PATH)
+        && message.interface().as_deref() == Some(AGENT_INTERFACE)
+}
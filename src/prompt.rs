@@ -0,0 +1,455 @@
+//! External prompt programs that collect the secret instead of the dialog's entry.
+//!
+//! Two protocols are supported:
+//! - `pinentry`: the Assuan protocol spoken by pinentry-* programs.
+//! - `plain`: the command gets the prompt in `BADGED_*` environment variables
+//!   and prints the secret as the first line of stdout, exiting non-zero to cancel.
+
+use std::future::Future;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use serde::Deserialize;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::secret::SecretString;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Pinentry,
+    Plain,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PromptConfig {
+    /// Program and arguments, e.g. `["pinentry-gnome3"]`.
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub protocol: Protocol,
+}
+
+/// What the prompt program returned.
+pub enum PromptResult {
+    Secret(SecretString),
+    Cancelled,
+    Failed(String),
+}
+
+/// A running prompt program. Dropping it kills the program.
+pub struct ExternalPrompt {
+    child: Arc<Mutex<Child>>,
+    result_rx: async_channel::Receiver<PromptResult>,
+}
+
+impl ExternalPrompt {
+    pub fn spawn(
+        config: &PromptConfig,
+        user: &str,
+        description: &str,
+        prompt: &str,
+    ) -> Result<Self, String> {
+        let (program, args) = config
+            .command
+            .split_first()
+            .ok_or("prompt command is empty")?;
+
+        let mut child = Command::new(program)
+            .args(args)
+            .env("BADGED_USER", user)
+            .env("BADGED_MESSAGE", description)
+            .env("BADGED_PROMPT", prompt)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("cannot run {program}: {err}"))?;
+
+        let stdin = child.stdin.take().ok_or("prompt stdin unavailable")?;
+        let stdout = child.stdout.take().ok_or("prompt stdout unavailable")?;
+        let child = Arc::new(Mutex::new(child));
+
+        let (result_tx, result_rx) = async_channel::bounded(1);
+        let protocol = config.protocol;
+        let description = description.to_owned();
+        let prompt = prompt.to_owned();
+        let waiter = Arc::clone(&child);
+        std::thread::spawn(move || {
+            let result = match protocol {
+                Protocol::Pinentry => run_pinentry(stdin, stdout, &description, &prompt),
+                Protocol::Plain => run_plain(stdin, stdout, &waiter),
+            };
+            let _ = result_tx.send_blocking(result);
+        });
+
+        Ok(Self { child, result_rx })
+    }
+
+    /// Resolves to the program's result once it has produced one. Doesn't
+    /// borrow the prompt, so it can be awaited while the prompt is stored.
+    pub fn result(&self) -> impl Future<Output = Option<PromptResult>> {
+        let result_rx = self.result_rx.clone();
+        async move { result_rx.recv().await.ok() }
+    }
+}
+
+impl Drop for ExternalPrompt {
+    fn drop(&mut self) {
+        if let Ok(mut child) = self.child.lock() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn run_pinentry(
+    mut stdin: impl Write,
+    stdout: impl Read,
+    description: &str,
+    prompt: &str,
+) -> PromptResult {
+    let mut reader = BufReader::new(stdout);
+    // Sized for the longest line up front, so a `D` line never reallocates
+    // and leaves a partial copy of the secret behind.
+    let mut line = Zeroizing::new(Vec::with_capacity(ASSUAN_LINE_MAX));
+    if let Err(err) = expect_ok(&mut reader, &mut line) {
+        return PromptResult::Failed(err);
+    }
+
+    let commands = [
+        "SETTITLE Authentication Required".to_owned(),
+        format!("SETDESC {}", assuan_escape(description)),
+        format!("SETPROMPT {}", assuan_escape(prompt)),
+    ];
+    for command in &commands {
+        if writeln!(stdin, "{command}").is_err() {
+            return PromptResult::Failed("pinentry closed its input".to_owned());
+        }
+        if let Err(err) = expect_ok(&mut reader, &mut line) {
+            return PromptResult::Failed(err);
+        }
+    }
+
+    if writeln!(stdin, "GETPIN").is_err() {
+        return PromptResult::Failed("pinentry closed its input".to_owned());
+    }
+    let mut secret = SecretString::default();
+    let result = loop {
+        match read_line(&mut reader, &mut line) {
+            Ok(true) => {}
+            Ok(false) => break PromptResult::Failed("pinentry exited".to_owned()),
+            Err(err) => break PromptResult::Failed(err),
+        }
+        match Response::parse(&line) {
+            Response::Data(data) => secret.push_str(assuan_unescape(data).expose()),
+            Response::Ok => break PromptResult::Secret(std::mem::take(&mut secret)),
+            Response::Err(err) if is_cancel(err) => break PromptResult::Cancelled,
+            Response::Err(err) => break PromptResult::Failed(error_text(err)),
+            Response::Other => continue,
+        }
+    };
+    let _ = writeln!(stdin, "BYE");
+    result
+}
+
+/// Wait for the `OK` (or `ERR`) that ends an Assuan response.
+fn expect_ok(reader: &mut impl BufRead, line: &mut Zeroizing<Vec<u8>>) -> Result<(), String> {
+    loop {
+        if !read_line(reader, line)? {
+            return Err("pinentry exited".to_owned());
+        }
+        match Response::parse(line) {
+            Response::Ok => return Ok(()),
+            Response::Err(err) => return Err(error_text(err)),
+            Response::Data(_) | Response::Other => continue,
+        }
+    }
+}
+
+/// Longest line Assuan allows, line ending included.
+const ASSUAN_LINE_MAX: usize = 1000;
+
+/// Read the next line into `line`, replacing what it held. Lines are read as
+/// bytes, since only `D` lines are escaped and comments may be in any
+/// encoding. Returns `Ok(false)` once pinentry's output ends.
+fn read_line(reader: &mut impl BufRead, line: &mut Zeroizing<Vec<u8>>) -> Result<bool, String> {
+    line.clear();
+    let read = reader
+        .take(ASSUAN_LINE_MAX as u64)
+        .read_until(b'\n', line)
+        .map_err(|err| err.to_string())?;
+    if read == ASSUAN_LINE_MAX && !line.ends_with(b"\n") {
+        return Err("pinentry sent a line longer than Assuan allows".to_owned());
+    }
+    Ok(read > 0)
+}
+
+/// One line of pinentry's output.
+#[derive(Debug, PartialEq, Eq)]
+enum Response<'a> {
+    Ok,
+    /// The error code and description after `ERR`.
+    Err(&'a [u8]),
+    /// Escaped data after `D`.
+    Data(&'a [u8]),
+    /// Status lines, comments and anything else not acted on.
+    Other,
+}
+
+impl<'a> Response<'a> {
+    /// Parse a line with or without its `\n` or `\r\n`. The keyword must
+    /// stand alone, so "OKAY" or "DATA" are not taken for "OK" or "D".
+    fn parse(line: &'a [u8]) -> Self {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let (keyword, rest) = match line.iter().position(|&byte| byte == b' ') {
+            Some(space) => (&line[..space], &line[space + 1..]),
+            None => (line, &line[line.len()..]),
+        };
+        match keyword {
+            b"OK" => Response::Ok,
+            b"ERR" => Response::Err(rest),
+            b"D" => Response::Data(rest),
+            _ => Response::Other,
+        }
+    }
+}
+
+/// Whether an `ERR` line reports GPG_ERR_CANCELED, sent when the user
+/// dismisses pinentry. The code's high bits name the error source, which
+/// varies between pinentry builds.
+fn is_cancel(err: &[u8]) -> bool {
+    const GPG_ERR_CANCELED: u32 = 99;
+    let code = err.split(|&byte| byte == b' ').next().unwrap_or_default();
+    !code.is_empty()
+        && code.iter().all(u8::is_ascii_digit)
+        && std::str::from_utf8(code)
+            .ok()
+            .and_then(|code| code.parse::<u32>().ok())
+            .is_some_and(|code| code & 0xffff == GPG_ERR_CANCELED)
+}
+
+fn error_text(err: &[u8]) -> String {
+    format!("ERR {}", String::from_utf8_lossy(err))
+}
+
+/// Bytes reserved for a `plain` program's output.
+const PLAIN_OUTPUT_CAPACITY: usize = 4096;
+
+fn run_plain(stdin: ChildStdin, mut stdout: ChildStdout, child: &Mutex<Child>) -> PromptResult {
+    drop(stdin);
+    // Read to EOF without holding the lock, so the program can still be killed.
+    // Room for any realistic answer up front, so reading doesn't reallocate
+    // and leave partial copies behind.
+    let mut output = Zeroizing::new(String::with_capacity(PLAIN_OUTPUT_CAPACITY));
+    let read = stdout.read_to_string(&mut output);
+
+    let status = child
+        .lock()
+        .map_err(|_| "prompt process lock poisoned".to_owned())
+        .and_then(|mut child| child.wait().map_err(|err| err.to_string()));
+    match (read, status) {
+        (Ok(_), Ok(status)) if status.success() => {
+            let secret = output.lines().next().unwrap_or_default().to_owned();
+            PromptResult::Secret(secret.into())
+        }
+        (_, Ok(_)) => PromptResult::Cancelled,
+        (Err(err), _) => PromptResult::Failed(err.to_string()),
+        (_, Err(err)) => PromptResult::Failed(err),
+    }
+}
+
+/// Percent-escape the characters Assuan doesn't allow in a line.
+fn assuan_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '%' => escaped.push_str("%25"),
+            '\n' => escaped.push_str("%0A"),
+            '\r' => escaped.push_str("%0D"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Undo `assuan_escape` on a `D` line. Only ever used on the secret, so
+/// the result is wiped on drop.
+fn assuan_unescape(bytes: &[u8]) -> SecretString {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && i + 2 < bytes.len()
+            && bytes[i + 1..i + 3].iter().all(u8::is_ascii_hexdigit)
+        {
+            // Both checked above, so this can't fail; `from_str_radix`
+            // alone would also take a sign like "+1".
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+            if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    match String::from_utf8(out) {
+        Ok(text) => text.into(),
+        Err(err) => {
+            let mut bytes = err.into_bytes();
+            let mut text = SecretString::default();
+            for chunk in bytes.utf8_chunks() {
+                text.push_str(chunk.valid());
+                if !chunk.invalid().is_empty() {
+                    text.push_str("\u{fffd}");
+                }
+            }
+            bytes.zeroize();
+            text
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Run a GETPIN conversation against canned pinentry output.
+    fn converse(output: &[u8]) -> PromptResult {
+        run_pinentry(
+            Vec::new(),
+            Cursor::new(output.to_vec()),
+            "description",
+            "prompt",
+        )
+    }
+
+    const GREETING: &str = "OK Pleased to meet you\nOK\nOK\nOK\n";
+
+    #[test]
+    fn unescapes_percent_sequences() {
+        assert_eq!(assuan_unescape(b"a%25b%0Ac").expose(), "a%b\nc");
+        assert_eq!(assuan_unescape(b"%c3%a9t%C3%A9").expose(), "été");
+    }
+
+    #[test]
+    fn keeps_incomplete_or_invalid_escapes() {
+        assert_eq!(assuan_unescape(b"100%").expose(), "100%");
+        assert_eq!(assuan_unescape(b"%4").expose(), "%4");
+        assert_eq!(assuan_unescape(b"%zz1").expose(), "%zz1");
+    }
+
+    #[test]
+    fn replaces_invalid_utf8() {
+        assert_eq!(assuan_unescape(b"a%FFb").expose(), "a\u{fffd}b");
+    }
+
+    #[test]
+    fn keeps_signed_escapes() {
+        assert_eq!(assuan_unescape(b"%+1").expose(), "%+1");
+        assert_eq!(assuan_unescape(b"%-1x").expose(), "%-1x");
+    }
+
+    #[test]
+    fn keywords_must_stand_alone() {
+        assert_eq!(Response::parse(b"OK"), Response::Ok);
+        assert_eq!(Response::parse(b"OK Pleased to meet you\r\n"), Response::Ok);
+        assert_eq!(Response::parse(b"OKAY\n"), Response::Other);
+        assert_eq!(Response::parse(b"D abc\r\n"), Response::Data(b"abc"));
+        assert_eq!(Response::parse(b"D\n"), Response::Data(b""));
+        assert_eq!(Response::parse(b"DATA abc"), Response::Other);
+        assert_eq!(Response::parse(b"ERR"), Response::Err(b""));
+        assert_eq!(Response::parse(b"ERRORS 1"), Response::Other);
+        assert_eq!(Response::parse(b"# \xff\xfe"), Response::Other);
+    }
+
+    #[test]
+    fn recognizes_cancel_from_any_source() {
+        assert!(is_cancel(b"83886179 Operation cancelled <Pinentry>"));
+        assert!(is_cancel(b"99"));
+        assert!(!is_cancel(b"838861790"));
+        assert!(!is_cancel(b"+99"));
+        assert!(!is_cancel(b"83886180 Timeout"));
+        assert!(!is_cancel(b""));
+    }
+
+    #[test]
+    fn reads_the_pin_across_data_lines() {
+        let output = format!("{GREETING}S PASSWORD_FROM_CACHE\r\nD ab%25\r\nD c%0Ad\r\nOK\r\n");
+        match converse(output.as_bytes()) {
+            PromptResult::Secret(secret) => assert_eq!(secret.expose(), "ab%c\nd"),
+            _ => panic!("expected a secret"),
+        }
+    }
+
+    #[test]
+    fn survives_undecodable_comments() {
+        let mut output = GREETING.as_bytes().to_vec();
+        output.extend_from_slice(b"# \xc3\x28 not UTF-8\nD 1234\nOK\n");
+        match converse(&output) {
+            PromptResult::Secret(secret) => assert_eq!(secret.expose(), "1234"),
+            _ => panic!("expected a secret"),
+        }
+    }
+
+    #[test]
+    fn refuses_overlong_lines() {
+        let output = format!("{GREETING}D {}\nOK\n", "x".repeat(ASSUAN_LINE_MAX));
+        assert!(matches!(
+            converse(output.as_bytes()),
+            PromptResult::Failed(_)
+        ));
+    }
+
+    #[test]
+    fn reports_cancel_and_early_exit() {
+        let cancelled = format!("{GREETING}ERR 83886179 Operation cancelled\n");
+        assert!(matches!(
+            converse(cancelled.as_bytes()),
+            PromptResult::Cancelled
+        ));
+        assert!(matches!(converse(b"OK\nOK\n"), PromptResult::Failed(_)));
+        assert!(matches!(
+            converse(GREETING.as_bytes()),
+            PromptResult::Failed(_)
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn escaping_round_trips(text in any::<String>()) {
+            let escaped = assuan_escape(&text);
+            prop_assert!(!escaped.contains(['\n', '\r']));
+            let unescaped = assuan_unescape(escaped.as_bytes());
+            prop_assert_eq!(unescaped.expose(), text.as_str());
+        }
+
+        #[test]
+        fn unescaping_stays_bounded(bytes in proptest::collection::vec(any::<u8>(), 0..4096)) {
+            // Each byte or escape yields at most one byte, or the three of a
+            // replacement character for an invalid one.
+            prop_assert!(assuan_unescape(&bytes).expose().len() <= bytes.len() * 3);
+        }
+
+        #[test]
+        fn parses_any_line(line in proptest::collection::vec(any::<u8>(), 0..2048)) {
+            match Response::parse(&line) {
+                Response::Data(rest) | Response::Err(rest) => prop_assert!(rest.len() < line.len()),
+                Response::Ok | Response::Other => {}
+            }
+        }
+
+        #[test]
+        fn never_hangs_or_panics_on_any_output(output in proptest::collection::vec(any::<u8>(), 0..8192)) {
+            let _ = converse(&output);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Diagnostic bundle for bug reports (`badged report` and the dialog's
+//! "Report a problem" button).
+//!
+//! Collects version info, the config file, doctor output and recent logs into
+//! a tarball. The user's name and home directory are masked throughout.
+
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use gtk4::glib;
+
+use crate::{config, doctor};
+
+/// How many trailing log lines to include.
+const LOG_LINES: usize = 500;
+
+/// Write `badged-report-<timestamp>.tar.gz` into `output_dir` and return its
+/// path. `log_file` is the `--log-file` in use, if any; otherwise the log is
+/// looked for where the README suggests putting it.
+pub fn create(output_dir: &Path, log_file: Option<&Path>) -> io::Result<PathBuf> {
+    let stamp = glib::DateTime::now_local()
+        .and_then(|now| now.format("%Y%m%d-%H%M%S"))
+        .map(|stamp| stamp.to_string())
+        .unwrap_or_else(|_| std::process::id().to_string());
+    let name = format!("badged-report-{stamp}");
+
+    // Staged privately: a fresh 0700 directory in the user's runtime
+    // directory, never one that someone else already created.
+    let staging = glib::user_runtime_dir().join(format!("{name}.{}", std::process::id()));
+    DirBuilder::new().mode(0o700).create(&staging)?;
+    let bundle = staging.join(&name);
+
+    let result = fs::create_dir(&bundle)
+        .and_then(|()| write_bundle(&bundle, log_file))
+        .and_then(|()| {
+            fs::create_dir_all(output_dir)?;
+            let archive = output_dir.join(format!("{name}.tar.gz"));
+            let status = Command::new("tar")
+                .arg("-czf")
+                .arg(&archive)
+                .arg("-C")
+                .arg(&staging)
+                .arg(&name)
+                .status()?;
+            if status.success() {
+                Ok(archive)
+            } else {
+                Err(io::Error::other(format!("tar exited with {status}")))
+            }
+        });
+
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+fn write_bundle(bundle: &Path, log_file: Option<&Path>) -> io::Result<()> {
+    let mut version = format!("badged {}\n", env!("CARGO_PKG_VERSION"));
+    for path in ["/proc/version", "/etc/os-release"] {
+        if let Ok(contents) = fs::read_to_string(path) {
+            version.push_str(&contents);
+        }
+    }
+    fs::write(bundle.join("version.txt"), redact(&version))?;
+
+    let doctor: String = doctor::run_checks()
+        .iter()
+        .map(|check| format!("{check}\n"))
+        .collect();
+    fs::write(bundle.join("doctor.txt"), redact(&doctor))?;
+
+    if let Some(contents) = config::config_path().and_then(|path| fs::read_to_string(path).ok()) {
+        fs::write(bundle.join("config.toml"), redact(&contents))?;
+    }
+
+    let log_file = log_file
+        .map(Path::to_owned)
+        .or_else(|| Some(config::state_dir()?.join("badged.log")));
+    if let Some(log) = log_file.and_then(|path| fs::read_to_string(path).ok()) {
+        fs::write(bundle.join("badged.log"), redact(&tail(&log)))?;
+    }
+
+    let journal = Command::new("journalctl")
+        .args(["--user", "--unit", "badged", "--no-pager", "--lines"])
+        .arg(LOG_LINES.to_string())
+        .output();
+    if let Some(output) = journal.ok().filter(|output| output.status.success()) {
+        let journal = String::from_utf8_lossy(&output.stdout);
+        fs::write(bundle.join("journal.txt"), redact(&journal))?;
+    }
+
+    Ok(())
+}
+
+fn tail(log: &str) -> String {
+    let lines: Vec<&str> = log.lines().collect();
+    let start = lines.len().saturating_sub(LOG_LINES);
+    lines[start..].join("\n")
+}
+
+/// Mask the home directory and user name.
+fn redact(text: &str) -> String {
+    let mut text = text.to_owned();
+    if let Some(home) = std::env::var_os("HOME").and_then(|home| home.into_string().ok()) {
+        if home.len() > 1 {
+            text = text.replace(&home, "~");
+        }
+    }
+    if let Some(user) = glib::user_name().to_str().filter(|user| !user.is_empty()) {
+        text = replace_word(&text, user, "<user>");
+    }
+    text
+}
+
+/// Replace `word` in `text` only where it stands on its own, so a short
+/// user name doesn't mangle the words that contain it.
+fn replace_word(text: &str, word: &str, with: &str) -> String {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+    let mut replaced = String::with_capacity(text.len());
+    let mut last = 0;
+    for (start, _) in text.match_indices(word) {
+        let end = start + word.len();
+        let before = text[..start].chars().next_back();
+        let after = text[end..].chars().next();
+        if before.is_some_and(is_word_char) || after.is_some_and(is_word_char) {
+            continue;
+        }
+        replaced.push_str(&text[last..start]);
+        replaced.push_str(with);
+        last = end;
+    }
+    replaced.push_str(&text[last..]);
+    replaced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::replace_word;
+
+    #[test]
+    fn replaces_whole_words_only() {
+        assert_eq!(
+            replace_word("al: alpha pal al.", "al", "<user>"),
+            "<user>: alpha pal <user>."
+        );
+    }
+
+    #[test]
+    fn keeps_names_that_extend_the_word() {
+        assert_eq!(
+            replace_word("al-x al_x xal", "al", "<user>"),
+            "al-x al_x xal"
+        );
+        assert_eq!(replace_word("(al)", "al", "<user>"), "(<user>)");
+    }
+}
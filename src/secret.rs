@@ -0,0 +1,86 @@
+//! Secrets (passwords, PINs, one-time codes) on their way to the PAM session.
+//!
+//! Copies this can't reach: the text in GTK's password entry (kept in
+//! non-pageable memory by GTK and cleared when the attempt ends), pinentry's
+//! replies in `BufReader`'s internal buffer, the pipe to the helper until it
+//! reads the answer, and whatever the helper and PAM keep from then on.
+
+use std::fmt;
+
+use zeroize::Zeroizing;
+
+/// A secret whose memory is wiped when it is dropped. It can't be cloned or
+/// displayed, and its `Debug` output is redacted, so it doesn't end up in a
+/// second buffer or a log line by accident.
+#[derive(Default)]
+pub struct SecretString(Zeroizing<String>);
+
+impl SecretString {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// The secret as a NUL-terminated C string for the PAM session, or
+    /// `None` if it contains a NUL byte and would be cut short.
+    pub fn nul_terminated(&self) -> Option<Zeroizing<Vec<u8>>> {
+        if self.0.contains('\0') {
+            return None;
+        }
+        let mut bytes = Zeroizing::new(Vec::with_capacity(self.0.len() + 1));
+        bytes.extend_from_slice(self.0.as_bytes());
+        bytes.push(0);
+        Some(bytes)
+    }
+
+    /// Append `text`. Growing never reallocates in place: the contents
+    /// move to a fresh buffer and the old one is wiped as it is dropped,
+    /// so no earlier copy is left in freed memory.
+    pub fn push_str(&mut self, text: &str) {
+        let needed = self.0.len() + text.len();
+        if needed > self.0.capacity() {
+            let mut grown = String::with_capacity(needed.max(2 * self.0.capacity()));
+            grown.push_str(&self.0);
+            self.0 = Zeroizing::new(grown);
+        }
+        self.0.push_str(text);
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(text: String) -> Self {
+        Self(Zeroizing::new(text))
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString(<redacted>)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SecretString;
+
+    #[test]
+    fn push_str_keeps_contents_across_growth() {
+        let mut secret = SecretString::default();
+        for digit in ["1", "2", "3", "4"] {
+            secret.push_str(digit);
+        }
+        secret.push_str(&"5".repeat(100));
+        assert_eq!(secret.expose(), format!("1234{}", "5".repeat(100)));
+    }
+
+    #[test]
+    fn nul_terminated_appends_a_single_nul() {
+        let secret = SecretString::from("hunter2".to_owned());
+        assert_eq!(secret.nul_terminated().unwrap().as_slice(), b"hunter2\0");
+    }
+
+    #[test]
+    fn nul_terminated_rejects_embedded_nul() {
+        let secret = SecretString::from("1234\0abc".to_owned());
+        assert!(secret.nul_terminated().is_none());
+    }
+}
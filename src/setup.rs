@@ -0,0 +1,309 @@
+//! First-run setup window, shown when there is no config file yet.
+//!
+//! Runs the doctor checks, offers to start badged with the session through an
+//! XDG autostart entry or a systemd user service, lets the user pick the
+//! dialog's look, and writes an initial config so it isn't shown again.
+//!
+//! Windows can't position themselves under GTK4, so the dialog's width is
+//! the only placement there is to pick.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+
+use gtk4::prelude::*;
+use gtk4::{gio, glib};
+
+use crate::config::{self, Config};
+use crate::doctor::{self, Status};
+use crate::theme::Theme;
+
+const AUTOSTART_ENTRY: &str = "[Desktop Entry]
+Type=Application
+Name=badged
+Comment=Polkit authentication agent
+Exec=badged
+NoDisplay=true
+X-GNOME-Autostart-Phase=Initialization
+";
+
+/// How badged should be started with the session, in dropdown order. The
+/// first choice installs nothing.
+const START_CHOICES: [&str; 3] = [
+    "Don't start automatically",
+    "Start when I log in (autostart entry)",
+    "Start with the graphical session (systemd user service)",
+];
+const START_AUTOSTART: u32 = 1;
+const START_SYSTEMD: u32 = 2;
+
+/// `theme-variant` nicks, in dropdown order.
+const VARIANTS: [&str; 3] = ["system", "light", "dark"];
+
+/// True when no config file exists yet.
+pub fn is_first_run() -> bool {
+    config::config_path().is_some_and(|path| !path.exists())
+}
+
+/// Show the window. `settings` is the dialog's GSettings, if its schema is
+/// installed; without it only the config file's choices are offered.
+pub fn show(app: &gtk4::Application, config: &Config, settings: Option<&gio::Settings>) {
+    let window = gtk4::Window::builder()
+        .application(app)
+        .title("Set up badged")
+        .default_width(420)
+        .resizable(false)
+        .build();
+
+    let main_box = gtk4::Box::builder()
+        .orientation(gtk4::Orientation::Vertical)
+        .spacing(8)
+        .margin_top(24)
+        .margin_bottom(24)
+        .margin_start(24)
+        .margin_end(24)
+        .build();
+
+    let intro = gtk4::Label::builder()
+        .label("badged answers polkit's password prompts for this session. These checks show whether everything it needs is in place.")
+        .wrap(true)
+        .xalign(0.0)
+        .build();
+    main_box.append(&intro);
+
+    let checks_box = gtk4::Box::builder()
+        .orientation(gtk4::Orientation::Vertical)
+        .spacing(4)
+        .margin_top(8)
+        .margin_bottom(8)
+        .build();
+    for check in doctor::run_checks() {
+        let icon = match check.status {
+            Status::Ok => "✅",
+            Status::Warning => "⚠️",
+            Status::Error => "❌",
+        };
+        let label = gtk4::Label::builder()
+            .label(format!("{icon} {}: {}", check.name, check.detail))
+            .wrap(true)
+            .xalign(0.0)
+            .build();
+        checks_box.append(&label);
+    }
+    main_box.append(&checks_box);
+
+    let start_dropdown = gtk4::DropDown::from_strings(&START_CHOICES);
+    start_dropdown.set_selected(if systemd_user_available() {
+        START_SYSTEMD
+    } else {
+        START_AUTOSTART
+    });
+    main_box.append(&start_dropdown);
+
+    let theme_names: Vec<&str> = Theme::ALL.iter().map(|theme| theme.name()).collect();
+    let theme_dropdown = gtk4::DropDown::from_strings(&theme_names);
+    let current_theme = Theme::ALL.iter().position(|theme| *theme == config.theme);
+    theme_dropdown.set_selected(current_theme.unwrap_or(0) as u32);
+    main_box.append(&labelled_row("Style preset:", &theme_dropdown));
+
+    let variant_dropdown = gtk4::DropDown::from_strings(&["Follow the desktop", "Light", "Dark"]);
+    let width_spin = gtk4::SpinButton::with_range(300.0, 1200.0, 20.0);
+    if let Some(settings) = settings {
+        let variant = settings.string("theme-variant");
+        let current = VARIANTS.iter().position(|nick| *nick == variant.as_str());
+        variant_dropdown.set_selected(current.unwrap_or(0) as u32);
+        width_spin.set_value(settings.int("window-width") as f64);
+        main_box.append(&labelled_row("Light or dark:", &variant_dropdown));
+        main_box.append(&labelled_row("Dialog width (pixels):", &width_spin));
+    }
+
+    let timeout_box = gtk4::Box::builder()
+        .orientation(gtk4::Orientation::Horizontal)
+        .spacing(12)
+        .build();
+    let timeout_label = gtk4::Label::builder()
+        .label("Offer a restart after the helper is silent for (seconds, 0 = never):")
+        .wrap(true)
+        .xalign(0.0)
+        .hexpand(true)
+        .build();
+    let timeout_spin = gtk4::SpinButton::with_range(0.0, 600.0, 5.0);
+    timeout_spin.set_value(config.helper_timeout as f64);
+    timeout_box.append(&timeout_label);
+    timeout_box.append(&timeout_spin);
+    main_box.append(&timeout_box);
+
+    let error_label = gtk4::Label::builder()
+        .wrap(true)
+        .xalign(0.0)
+        .visible(false)
+        .build();
+    error_label.add_css_class("error");
+    main_box.append(&error_label);
+
+    let button_box = gtk4::Box::builder()
+        .orientation(gtk4::Orientation::Horizontal)
+        .spacing(8)
+        .halign(gtk4::Align::End)
+        .margin_top(16)
+        .build();
+    let skip_button = gtk4::Button::with_label("Not now");
+    let save_button = gtk4::Button::with_label("Save");
+    save_button.add_css_class("suggested-action");
+    button_box.append(&skip_button);
+    button_box.append(&save_button);
+    main_box.append(&button_box);
+
+    window.set_child(Some(&main_box));
+    window.set_default_widget(Some(&save_button));
+
+    {
+        let window_c = window.clone();
+        skip_button.connect_clicked(move |_| window_c.close());
+    }
+
+    {
+        let window_c = window.clone();
+        let settings = settings.cloned();
+        save_button.connect_clicked(move |_| {
+            if let Some(settings) = &settings {
+                let variant = VARIANTS[variant_dropdown.selected() as usize % VARIANTS.len()];
+                let _ = settings.set_string("theme-variant", variant);
+                let _ = settings.set_int("window-width", width_spin.value() as i32);
+            }
+            let theme = Theme::ALL
+                .get(theme_dropdown.selected() as usize)
+                .copied()
+                .unwrap_or_default();
+            let result =
+                write_config(timeout_spin.value() as u64, theme).and_then(
+                    |()| match start_dropdown.selected() {
+                        START_AUTOSTART => install_autostart(),
+                        START_SYSTEMD => install_systemd_unit(),
+                        _ => Ok(()),
+                    },
+                );
+            match result {
+                Ok(()) => window_c.close(),
+                Err(err) => {
+                    log!("[setup] Failed to save: {err}");
+                    error_label.set_label(&format!("Could not save: {err}"));
+                    error_label.set_visible(true);
+                }
+            }
+        });
+    }
+
+    window.present();
+}
+
+/// `widget` with a label in front of it.
+fn labelled_row(label: &str, widget: &impl IsA<gtk4::Widget>) -> gtk4::Box {
+    let row = gtk4::Box::builder()
+        .orientation(gtk4::Orientation::Horizontal)
+        .spacing(12)
+        .build();
+    let label = gtk4::Label::builder()
+        .label(label)
+        .xalign(0.0)
+        .hexpand(true)
+        .build();
+    row.append(&label);
+    row.append(widget);
+    row
+}
+
+fn write_config(helper_timeout: u64, theme: Theme) -> io::Result<()> {
+    let path = config::config_path().ok_or_else(|| io::Error::other("no config directory"))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let contents = format!(
+        "# badged configuration; see the README for every available key.\n\
+         helper_timeout = {helper_timeout}\n\
+         theme = \"{}\"\n",
+        theme.name()
+    );
+    fs::write(&path, contents)?;
+    log!("[setup] Wrote {}", path.display());
+    Ok(())
+}
+
+fn autostart_path() -> Option<PathBuf> {
+    Some(
+        config::config_path()?
+            .parent()?
+            .parent()?
+            .join("autostart/badged.desktop"),
+    )
+}
+
+fn install_autostart() -> io::Result<()> {
+    let path = autostart_path().ok_or_else(|| io::Error::other("no config directory"))?;
+    if path.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, AUTOSTART_ENTRY)?;
+    log!("[setup] Installed {}", path.display());
+    Ok(())
+}
+
+/// Whether a systemd user manager runs this session.
+fn systemd_user_available() -> bool {
+    glib::user_runtime_dir().join("systemd").is_dir()
+}
+
+fn systemd_unit() -> String {
+    let exec = std::env::current_exe()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| "badged".to_owned());
+    format!(
+        "[Unit]\n\
+         Description=badged - Polkit Authentication Agent\n\
+         PartOf=graphical-session.target\n\
+         After=graphical-session.target\n\
+         \n\
+         [Service]\n\
+         Type=simple\n\
+         ExecStart={exec}\n\
+         Restart=on-failure\n\
+         RestartSec=3\n\
+         \n\
+         [Install]\n\
+         WantedBy=graphical-session.target\n"
+    )
+}
+
+/// Install and enable a user unit; it takes over from the next login, as
+/// this instance already serves the current one.
+fn install_systemd_unit() -> io::Result<()> {
+    let path = config::config_path()
+        .and_then(|path| Some(path.parent()?.parent()?.join("systemd/user/badged.service")))
+        .ok_or_else(|| io::Error::other("no config directory"))?;
+    if !path.exists() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, systemd_unit())?;
+        log!("[setup] Installed {}", path.display());
+    }
+    let status = Command::new("systemctl")
+        .args(["--user", "daemon-reload"])
+        .status()
+        .and_then(|_| {
+            Command::new("systemctl")
+                .args(["--user", "enable", "badged.service"])
+                .status()
+        })?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "systemctl --user enable exited with {status}"
+        )))
+    }
+}
@@ -0,0 +1,130 @@
+//! Per-action prompt counters, persisted across runs.
+//!
+//! Stored as tab-separated `action_id prompts successes failures` lines in
+//! `$XDG_STATE_HOME/badged/actions.tsv`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::config;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ActionStats {
+    pub prompts: u64,
+    pub successes: u64,
+    pub failures: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+    Prompted,
+    Succeeded,
+    Failed,
+}
+
+pub fn stats_path() -> Option<PathBuf> {
+    Some(config::state_dir()?.join("actions.tsv"))
+}
+
+/// Bump the counter for `outcome` on `action_id` and persist it.
+pub fn record(action_id: &str, outcome: Outcome) {
+    if action_id.is_empty() {
+        return;
+    }
+
+    let mut all = load();
+    let entry = all.entry(action_id.to_owned()).or_default();
+    match outcome {
+        Outcome::Prompted => entry.prompts += 1,
+        Outcome::Succeeded => entry.successes += 1,
+        Outcome::Failed => entry.failures += 1,
+    }
+
+    if let Err(err) = save(&all) {
+        log!("[stats] Failed to save action statistics: {err}");
+    }
+}
+
+pub fn load() -> BTreeMap<String, ActionStats> {
+    let Some(contents) = stats_path().and_then(|path| fs::read_to_string(path).ok()) else {
+        return BTreeMap::new();
+    };
+
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let action_id = fields.next()?.to_owned();
+            let mut count = || fields.next()?.parse::<u64>().ok();
+            let stats = ActionStats {
+                prompts: count()?,
+                successes: count()?,
+                failures: count()?,
+            };
+            Some((action_id, stats))
+        })
+        .collect()
+}
+
+fn save(all: &BTreeMap<String, ActionStats>) -> io::Result<()> {
+    let path = stats_path().ok_or_else(|| io::Error::other("no state directory"))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let contents: String = all
+        .iter()
+        .map(|(action_id, stats)| {
+            format!(
+                "{action_id}\t{}\t{}\t{}\n",
+                stats.prompts, stats.successes, stats.failures
+            )
+        })
+        .collect();
+
+    let tmp = path.with_extension("tsv.tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(tmp, path)
+}
+
+/// Print the per-action table for `badged status --actions`, most prompted first.
+pub fn print_actions() {
+    let mut all: Vec<_> = load().into_iter().collect();
+    if all.is_empty() {
+        println!("No authentication prompts recorded yet.");
+        return;
+    }
+    all.sort_by(|a, b| b.1.prompts.cmp(&a.1.prompts).then_with(|| a.0.cmp(&b.0)));
+
+    let width = all
+        .iter()
+        .map(|(action_id, _)| action_id.len())
+        .max()
+        .unwrap_or(0)
+        .max("ACTION".len());
+    println!(
+        "{:<width$}  {:>8}  {:>9}  {:>8}",
+        "ACTION", "PROMPTS", "SUCCEEDED", "FAILED"
+    );
+    for (action_id, stats) in all {
+        println!(
+            "{:<width$}  {:>8}  {:>9}  {:>8}",
+            action_id, stats.prompts, stats.successes, stats.failures
+        );
+    }
+}
+
+/// Print the totals for plain `badged status`.
+pub fn print_summary() {
+    let all = load();
+    let prompts: u64 = all.values().map(|stats| stats.prompts).sum();
+    println!(
+        "{prompts} authentication prompts recorded across {} actions",
+        all.len()
+    );
+    if let Some(path) = stats_path() {
+        println!("Statistics: {}", path.display());
+    }
+}
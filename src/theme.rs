@@ -0,0 +1,131 @@
+//! Built-in style presets (`theme` in the config), layered over the dialog's
+//! own CSS and switchable at runtime through the control interface.
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Theme {
+    /// The dialog's own look, with nothing layered over it.
+    #[default]
+    Default,
+    /// No frame around the status, a smaller icon.
+    Minimal,
+    /// Roomier, rounder and closer to GNOME Shell's dialogs.
+    Gnome,
+    /// Full-opacity text and an outlined status frame.
+    HighContrast,
+    /// Everything a few sizes up.
+    LargeText,
+}
+
+impl Theme {
+    pub const ALL: [Theme; 5] = [
+        Theme::Default,
+        Theme::Minimal,
+        Theme::Gnome,
+        Theme::HighContrast,
+        Theme::LargeText,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Theme::Default => "default",
+            Theme::Minimal => "minimal",
+            Theme::Gnome => "gnome",
+            Theme::HighContrast => "high-contrast",
+            Theme::LargeText => "large-text",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|theme| theme.name() == name)
+    }
+
+    pub fn css(self) -> &'static str {
+        match self {
+            Theme::Default => "",
+            Theme::Minimal => MINIMAL_CSS,
+            Theme::Gnome => GNOME_CSS,
+            Theme::HighContrast => HIGH_CONTRAST_CSS,
+            Theme::LargeText => LARGE_TEXT_CSS,
+        }
+    }
+}
+
+const MINIMAL_CSS: &str = r#"
+.fingerprint-frame {
+    background-color: transparent;
+    padding: 4px 0;
+}
+
+.fingerprint-label {
+    font-size: 32px;
+    margin-bottom: 4px;
+}
+
+.fingerprint-icon {
+    -gtk-icon-size: 32px;
+    margin-bottom: 4px;
+}
+"#;
+
+const GNOME_CSS: &str = r#"
+.auth-message {
+    font-size: 15px;
+    opacity: 1;
+}
+
+.fingerprint-frame {
+    border-radius: 18px;
+    padding: 24px 48px;
+}
+
+button {
+    min-height: 36px;
+    border-radius: 9px;
+}
+"#;
+
+const HIGH_CONTRAST_CSS: &str = r#"
+.auth-message,
+.requested-by,
+.separator-label,
+.fingerprint-countdown {
+    opacity: 1;
+}
+
+.fingerprint-frame {
+    background-color: transparent;
+    border: 2px solid currentColor;
+}
+
+.fingerprint-status.error,
+.fingerprint-status.success {
+    font-weight: bold;
+}
+"#;
+
+const LARGE_TEXT_CSS: &str = r#"
+window {
+    font-size: 16px;
+}
+
+.auth-message,
+.fingerprint-status {
+    font-size: 17px;
+}
+
+.requested-by,
+.separator-label {
+    font-size: 15px;
+}
+
+.fingerprint-label {
+    font-size: 64px;
+}
+
+.fingerprint-icon {
+    -gtk-icon-size: 64px;
+}
+"#;
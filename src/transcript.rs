@@ -0,0 +1,87 @@
+//! Opt-in recording of PAM conversations (`record_transcripts`).
+//!
+//! Each request gets a file in `$XDG_STATE_HOME/badged/transcripts/` listing
+//! what the helper said and when, for reproducing PAM stack problems.
+//! Responses are never written, only that one was given.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use crate::config;
+
+/// Transcripts kept on disk; older ones are removed as new ones start.
+const MAX_TRANSCRIPTS: usize = 50;
+
+pub struct Transcript {
+    file: File,
+    started: Instant,
+}
+
+impl Transcript {
+    /// Start a transcript for request `request_id`. Failures are logged and
+    /// leave the request unrecorded.
+    pub fn start(request_id: u64, action_id: &str) -> Option<Self> {
+        match Self::create(request_id, action_id) {
+            Ok(transcript) => Some(transcript),
+            Err(err) => {
+                log!("[transcript] Cannot record request {request_id}: {err}");
+                None
+            }
+        }
+    }
+
+    fn create(request_id: u64, action_id: &str) -> io::Result<Self> {
+        let dir = transcripts_dir().ok_or_else(|| io::Error::other("no state directory"))?;
+        fs::create_dir_all(&dir)?;
+        prune(&dir);
+
+        let stamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let mut file = File::create(dir.join(format!("{stamp}-{request_id}.log")))?;
+        writeln!(
+            file,
+            "# badged {} request #{request_id}",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(file, "# action {action_id}")?;
+        Ok(Self {
+            file,
+            started: Instant::now(),
+        })
+    }
+
+    /// Append one event, stamped with the time since the request started.
+    pub fn record(&mut self, kind: &str, text: &str) {
+        let elapsed = self.started.elapsed().as_millis();
+        let text = crate::logging::redact(text);
+        if let Err(err) = writeln!(self.file, "{elapsed:>8}ms {kind} {text:?}") {
+            log!("[transcript] Write failed: {err}");
+        }
+    }
+}
+
+fn transcripts_dir() -> Option<PathBuf> {
+    Some(config::state_dir()?.join("transcripts"))
+}
+
+/// Keep the newest `MAX_TRANSCRIPTS - 1` files, making room for one more.
+fn prune(dir: &std::path::Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .collect();
+    if files.len() < MAX_TRANSCRIPTS {
+        return;
+    }
+    // Names start with a unix timestamp, so they sort oldest first.
+    files.sort();
+    for path in &files[..=files.len() - MAX_TRANSCRIPTS] {
+        let _ = fs::remove_file(path);
+    }
+}
@@ -1,26 +1,41 @@
 //! GTK4 authentication dialog UI.
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
-use std::sync::mpsc;
 
-use gtk4::glib;
+use gtk4::glib::translate::ToGlibPtr;
 use gtk4::prelude::*;
+use gtk4::subclass::prelude::ObjectSubclassIsExt;
+use gtk4::{gio, glib};
+#[cfg(feature = "adwaita")]
+use libadwaita as adw;
 
+use crate::appearance;
+use crate::config::{self, Config};
+use crate::dialog::BadgedAuthDialog;
+use crate::doctor;
 use crate::listener::{SharedState, UiEvent};
+use crate::pam::{self, AuthMethod, FingerprintState, FPRINTD_MAX_TRIES, FPRINTD_TIMEOUT};
+use crate::prompt::{ExternalPrompt, PromptResult};
+use crate::report;
+use crate::secret::SecretString;
+use crate::setup;
 
 pub struct UiChannels {
-    pub event_rx: mpsc::Receiver<UiEvent>,
+    pub event_rx: async_channel::Receiver<UiEvent>,
     pub shared: Rc<SharedState>,
 }
 
-const CSS: &str = r#"
-.auth-header {
-    font-size: 18px;
-    font-weight: bold;
-    margin-bottom: 4px;
-}
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
 
+/// GSettings schema for appearance and behavior; optional, see `gsettings`.
+const SCHEMA_ID: &str = "dev.badged";
+
+/// Shown in the header bar when the action doesn't provide an icon.
+const DEFAULT_ICON_NAME: &str = "dialog-password";
+
+const CSS: &str = r#"
 .auth-message {
     font-size: 13px;
     opacity: 0.8;
@@ -39,6 +54,48 @@ const CSS: &str = r#"
     margin-bottom: 8px;
 }
 
+.fingerprint-icon {
+    -gtk-icon-size: 48px;
+    margin-bottom: 8px;
+}
+
+.fingerprint-icon.error {
+    color: #c01c28;
+}
+
+.fingerprint-icon.success {
+    color: #26a269;
+}
+
+@keyframes fingerprint-pulse {
+    from { opacity: 1; }
+    to { opacity: 0.35; }
+}
+
+@keyframes fingerprint-shake {
+    0% { transform: translateX(0); }
+    25% { transform: translateX(-6px); }
+    75% { transform: translateX(6px); }
+    100% { transform: translateX(0); }
+}
+
+@keyframes fingerprint-pop {
+    from { transform: scale(0.6); opacity: 0; }
+    to { transform: scale(1); opacity: 1; }
+}
+
+.fingerprint-scanning {
+    animation: fingerprint-pulse 1s ease-in-out infinite alternate;
+}
+
+.fingerprint-shake {
+    animation: fingerprint-shake 120ms ease-in-out 3;
+}
+
+.fingerprint-pop {
+    animation: fingerprint-pop 250ms ease-out;
+}
+
 .fingerprint-status {
     font-size: 13px;
 }
@@ -51,371 +108,1576 @@ const CSS: &str = r#"
     color: #26a269;
 }
 
+.fingerprint-countdown {
+    margin-top: 8px;
+    opacity: 0.5;
+}
+
+.requested-by {
+    font-size: 12px;
+    opacity: 0.6;
+    margin-bottom: 8px;
+}
+
 .separator-label {
     opacity: 0.6;
     font-size: 12px;
     margin: 8px 0;
 }
+
+.pin-pad button {
+    min-width: 64px;
+    min-height: 48px;
+    font-size: 18px;
+}
+"#;
+
+/// Status colors that stay readable on dark themes, layered over `CSS`
+/// while the dialog is dark.
+const DARK_CSS: &str = r#"
+.fingerprint-status.error,
+.fingerprint-icon.error {
+    color: #ff7b63;
+}
+
+.fingerprint-status.success,
+.fingerprint-icon.success {
+    color: #8ff0a4;
+}
 "#;
 
+/// How long the one-shot status icon animations run, with some slack.
+const SHAKE_DURATION: std::time::Duration = std::time::Duration::from_millis(400);
+const POP_DURATION: std::time::Duration = std::time::Duration::from_millis(300);
+
+/// How long a transient PAM error (a missed finger) stays on screen.
+const TRANSIENT_ERROR_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
 /// Run the GTK4 UI event loop (blocking).
-pub fn run(channels: UiChannels) {
+///
+/// The application is unique on its id: only the primary instance calls
+/// `start_agent` (registering with polkit) and builds the dialog. Launching
+/// badged again just activates the running instance and exits. Whatever
+/// `start_agent` returns is kept alive until the application shuts down.
+///
+/// The primary instance allows replacement: `badged --replace` (`replace`)
+/// takes over its name, and the old instance quits and unregisters.
+pub fn run<R: 'static>(
+    config: Rc<Config>,
+    channels: UiChannels,
+    replace: bool,
+    start_agent: impl FnOnce() -> Result<R, String> + 'static,
+) {
+    let mut flags = gio::ApplicationFlags::ALLOW_REPLACEMENT;
+    if replace {
+        flags |= gio::ApplicationFlags::REPLACE;
+    }
+    #[cfg(not(feature = "adwaita"))]
     let app = gtk4::Application::builder()
-        .application_id("org.freedesktop.badged.Agent")
-        .flags(gtk4::gio::ApplicationFlags::NON_UNIQUE)
+        .application_id(&config.app_id)
+        .flags(flags)
         .build();
+    // Sets libadwaita up on startup: its stylesheet and style manager.
+    #[cfg(feature = "adwaita")]
+    let app: gtk4::Application = adw::Application::builder()
+        .application_id(&config.app_id)
+        .flags(flags)
+        .build()
+        .upcast();
+
+    // SIGTERM/SIGINT take the normal shutdown path, so in-flight requests
+    // get cancelled replies and the agent unregisters from polkit.
+    for (signum, name) in [(SIGINT, "SIGINT"), (SIGTERM, "SIGTERM")] {
+        let app = app.downgrade();
+        glib::unix_signal_add_local(signum, move || {
+            log!("[ui] Received {name}, shutting down");
+            if let Some(app) = app.upgrade() {
+                app.quit();
+            }
+            glib::ControlFlow::Continue
+        });
+    }
+
+    app.connect_name_lost(|app| {
+        log!("[ui] Replaced by a new badged instance; quitting");
+        app.quit();
+        true
+    });
 
-    let channels = Rc::new(std::cell::RefCell::new(Some(channels)));
+    let channels = Rc::new(RefCell::new(Some(channels)));
+    let start_agent = RefCell::new(Some(start_agent));
+    let agent: Rc<RefCell<Option<R>>> = Rc::new(RefCell::new(None));
+    let hold: Rc<RefCell<Option<gtk4::gio::ApplicationHoldGuard>>> = Rc::new(RefCell::new(None));
+    let appearance: Rc<RefCell<Option<appearance::Watch>>> = Rc::new(RefCell::new(None));
+    let failed = Rc::new(Cell::new(false));
 
-    let app_clone = app.clone();
-    app.connect_startup(move |_| {
-        load_css();
-        app_clone.activate();
+    app.connect_handle_local_options(|app, _| {
+        if app.register(gtk4::gio::Cancellable::NONE).is_ok() && app.is_remote() {
+            log!(
+                "[ui] badged is already running; activating it instead (use --replace to take over)"
+            );
+        }
+        std::ops::ControlFlow::Continue(())
     });
 
-    app.connect_activate(move |app| {
-        let (window, widgets) = build_window(app);
-        if let Some(ch) = channels.borrow_mut().take() {
-            setup_ui(window, widgets, ch);
+    {
+        let agent = agent.clone();
+        let hold = hold.clone();
+        let appearance = appearance.clone();
+        let failed_c = failed.clone();
+        app.connect_startup(move |app| {
+            let Some(start_agent) = start_agent.borrow_mut().take() else {
+                return;
+            };
+            match start_agent() {
+                Ok(registration) => *agent.borrow_mut() = Some(registration),
+                Err(err) => {
+                    // The window keeps the application alive until it is
+                    // closed; then GTK shuts down normally.
+                    log!("[ui] Failed to start the agent: {err}");
+                    failed_c.set(true);
+                    show_error(Some(app), "badged Cannot Start", &err);
+                    return;
+                }
+            }
+
+            let settings = gsettings();
+            *appearance.borrow_mut() = Some(load_css(settings.clone()));
+            let (window, widgets) = build_window(app, settings.as_ref());
+            if setup::is_first_run() {
+                setup::show(app, &config, settings.as_ref());
+            }
+            if let Some(ch) = channels.borrow_mut().take() {
+                setup_ui(window, widgets, ch, settings);
+            }
+            // Stay alive without any visible window until asked to quit.
+            *hold.borrow_mut() = Some(app.hold());
+        });
+    }
+
+    app.connect_activate(|app| {
+        // Our own launch and every later `badged` invocation end up here.
+        match app.windows().into_iter().find(|window| window.is_visible()) {
+            Some(window) => window.present(),
+            None => log!("[ui] Activated with no pending authentication request"),
         }
     });
 
-    let _hold = app.hold();
+    app.connect_shutdown(move |_| {
+        hold.borrow_mut().take();
+        // Dropping the registration unregisters from polkit.
+        agent.borrow_mut().take();
+    });
+
     app.run_with_args::<&str>(&[]);
+    // Exit non-zero so a supervising service manager sees the failure.
+    if failed.get() {
+        std::process::exit(1);
+    }
 }
 
-fn load_css() {
+/// badged's GSettings, or `None` when the schema isn't installed (e.g. when
+/// run straight from a build tree); the defaults apply then.
+fn gsettings() -> Option<gio::Settings> {
+    let Some(schema) =
+        gio::SettingsSchemaSource::default().and_then(|source| source.lookup(SCHEMA_ID, true))
+    else {
+        log!("[ui] GSettings schema {SCHEMA_ID} not installed, using defaults");
+        return None;
+    };
+    Some(gio::Settings::new_full(
+        &schema,
+        None::<&gio::SettingsBackend>,
+        None,
+    ))
+}
+
+/// Install the dialog's CSS, adding `DARK_CSS` while the dialog is dark.
+fn load_css(settings: Option<gio::Settings>) -> appearance::Watch {
+    let display = gtk4::gdk::Display::default().expect("Could not get default display");
     let provider = gtk4::CssProvider::new();
     provider.load_from_data(CSS);
     gtk4::style_context_add_provider_for_display(
-        &gtk4::gdk::Display::default().expect("Could not get default display"),
+        &display,
         &provider,
         gtk4::STYLE_PROVIDER_PRIORITY_APPLICATION,
     );
+
+    let dark_provider = gtk4::CssProvider::new();
+    dark_provider.load_from_data(DARK_CSS);
+    let applied = Cell::new(false);
+    appearance::watch(settings, move |dark| {
+        if applied.replace(dark) == dark {
+            return;
+        }
+        log!(
+            "[ui] Switching to the {} style",
+            if dark { "dark" } else { "light" }
+        );
+        if dark {
+            gtk4::style_context_add_provider_for_display(
+                &display,
+                &dark_provider,
+                gtk4::STYLE_PROVIDER_PRIORITY_APPLICATION + 1,
+            );
+        } else {
+            gtk4::style_context_remove_provider_for_display(&display, &dark_provider);
+        }
+    })
 }
 
+/// PAM status text, one line per authentication method in the conversation.
+///
+/// The first method seen uses the main status label; methods that show up
+/// alongside it (e.g. an OTP prompt next to the fingerprint reader) get their
+/// own rows so neither overwrites the other.
+struct StatusLines {
+    primary: gtk4::Label,
+    extra: gtk4::Box,
+    lines: RefCell<Vec<(AuthMethod, gtk4::Label)>>,
+    /// Fatal errors, kept on their own rows until the attempt ends.
+    pinned: RefCell<Vec<gtk4::Label>>,
+}
+
+impl StatusLines {
+    fn show(&self, method: AuthMethod, text: &str, is_error: bool) {
+        let label = self.line(method);
+        // Rows below the first carry their method's glyph, since the big
+        // icon can only show one.
+        match method.icon() {
+            Some(glyph) if label != self.primary => label.set_label(&format!("{glyph} {text}")),
+            _ => label.set_label(text),
+        }
+        label.remove_css_class("success");
+        if is_error {
+            label.add_css_class("error");
+        } else {
+            label.remove_css_class("error");
+        }
+    }
+
+    /// Show an error PAM reported. Transient ones (a missed finger, a
+    /// timeout) clear themselves after a moment; anything else is pinned
+    /// on a row of its own so the next message can't hide it.
+    fn show_error(&self, method: AuthMethod, text: &str) {
+        if pam::is_transient_error(text) {
+            let label = self.line(method);
+            let previous = if label.has_css_class("error") {
+                String::new()
+            } else {
+                label.label().to_string()
+            };
+            self.show(method, text, true);
+            let shown = label.label();
+            glib::timeout_add_local_once(TRANSIENT_ERROR_TIMEOUT, move || {
+                if label.label() == shown {
+                    label.set_label(&previous);
+                    label.remove_css_class("error");
+                }
+            });
+            return;
+        }
+
+        let text = format!("❌ {text}");
+        if self
+            .pinned
+            .borrow()
+            .iter()
+            .any(|label| label.label() == text)
+        {
+            return;
+        }
+        let label = gtk4::Label::builder()
+            .label(text)
+            .wrap(true)
+            .halign(gtk4::Align::Center)
+            .build();
+        label.add_css_class("fingerprint-status");
+        label.add_css_class("error");
+        self.extra.append(&label);
+        self.pinned.borrow_mut().push(label);
+    }
+
+    /// Drop the pinned errors, e.g. once the attempt they belong to failed.
+    fn clear_pinned(&self) {
+        for label in self.pinned.borrow_mut().drain(..) {
+            self.extra.remove(&label);
+        }
+    }
+
+    /// The row for `method`, created on first use.
+    fn line(&self, method: AuthMethod) -> gtk4::Label {
+        {
+            let mut lines = self.lines.borrow_mut();
+            match lines.iter().find(|(known, _)| *known == method) {
+                Some((_, label)) => label.clone(),
+                None => {
+                    let label = if lines.is_empty() {
+                        self.primary.clone()
+                    } else {
+                        let label = gtk4::Label::builder()
+                            .wrap(true)
+                            .halign(gtk4::Align::Center)
+                            .build();
+                        label.add_css_class("fingerprint-status");
+                        self.extra.append(&label);
+                        label
+                    };
+                    lines.push((method, label.clone()));
+                    label
+                }
+            }
+        }
+    }
+
+    /// Whether `method` has spoken up in this attempt.
+    fn has(&self, method: AuthMethod) -> bool {
+        self.lines
+            .borrow()
+            .iter()
+            .any(|(known, _)| *known == method)
+    }
+
+    /// Whether a fingerprint, security key or similar step has spoken up.
+    fn has_biometric(&self) -> bool {
+        self.lines
+            .borrow()
+            .iter()
+            .any(|(method, _)| *method != AuthMethod::Other)
+    }
+
+    /// Forget all methods and drop the extra rows.
+    fn reset(&self) {
+        for (_, label) in self.lines.borrow_mut().drain(..) {
+            if label != self.primary {
+                self.extra.remove(&label);
+            }
+        }
+        self.clear_pinned();
+    }
+}
+
+/// Answers already given in this attempt, each on a row under its own
+/// prompt, so a chained conversation ("Current password", "New password",
+/// "Retype new password") keeps every field in view.
+struct AnsweredPrompts {
+    container: gtk4::Box,
+}
+
+impl AnsweredPrompts {
+    /// Move the answer in `entry` onto a read-only row labelled like
+    /// `label`. The buffer itself moves, so the secret isn't copied, and
+    /// `entry` is left with a fresh, empty one.
+    fn keep(&self, label: &gtk4::Label, entry: &gtk4::PasswordEntry) {
+        let Some(text) = entry_text(entry) else {
+            return;
+        };
+        let row = gtk4::Box::builder()
+            .orientation(gtk4::Orientation::Horizontal)
+            .spacing(12)
+            .build();
+        let row_label = gtk4::Label::builder()
+            .label(label.label())
+            .width_chars(10)
+            .max_width_chars(24)
+            .wrap(true)
+            .xalign(0.0)
+            .build();
+        row_label.add_css_class("dim-label");
+        let row_entry = gtk4::PasswordEntry::builder()
+            .show_peek_icon(true)
+            .hexpand(true)
+            .build();
+        row_entry.set_editable(false);
+        if let Some(row_text) = entry_text(&row_entry) {
+            row_text.set_buffer(&text.buffer());
+        }
+        text.set_buffer(&gtk4::PasswordEntryBuffer::new());
+        row.append(&row_label);
+        row.append(&row_entry);
+        self.container.append(&row);
+        self.container.set_visible(true);
+    }
+
+    /// Drop the rows when the attempt ends or starts over.
+    fn clear(&self) {
+        while let Some(row) = self.container.first_child() {
+            self.container.remove(&row);
+        }
+        self.container.set_visible(false);
+    }
+}
+
+/// The big status glyph above the PAM messages. It is shown as a symbolic
+/// icon when the icon theme has a match and as the emoji otherwise; either
+/// way the emoji names the current glyph.
+#[derive(Clone)]
+struct StatusIcon {
+    container: gtk4::Box,
+    image: gtk4::Image,
+    label: gtk4::Label,
+}
+
+impl StatusIcon {
+    fn set_glyph(&self, glyph: &str) {
+        // Whatever the reader was doing is over; callers restart the pulse.
+        self.container.remove_css_class("fingerprint-scanning");
+        self.label.set_label(glyph);
+        let icon_name = symbolic_icon_name(glyph);
+        if let Some(icon_name) = icon_name {
+            self.image.set_icon_name(Some(icon_name));
+        }
+        self.image.set_visible(icon_name.is_some());
+        self.label.set_visible(icon_name.is_none());
+
+        self.image.remove_css_class("error");
+        self.image.remove_css_class("success");
+        match glyph {
+            "❌" | "⚠️" => self.image.add_css_class("error"),
+            "✅" => self.image.add_css_class("success"),
+            _ => {}
+        }
+    }
+
+    fn glyph(&self) -> glib::GString {
+        self.label.label()
+    }
+
+    /// Pulse while the reader waits for a finger.
+    fn set_scanning(&self, scanning: bool) {
+        if scanning {
+            self.container.add_css_class("fingerprint-scanning");
+        } else {
+            self.container.remove_css_class("fingerprint-scanning");
+        }
+    }
+
+    /// Run a one-shot animation (`fingerprint-shake`, `fingerprint-pop`).
+    /// The class is taken off again afterwards so the next one replays.
+    fn play(&self, class: &'static str, duration: std::time::Duration) {
+        self.container.add_css_class(class);
+        let container = self.container.clone();
+        glib::timeout_add_local_once(duration, move || container.remove_css_class(class));
+    }
+
+    fn set_visible(&self, visible: bool) {
+        self.container.set_visible(visible);
+    }
+}
+
+/// The first symbolic icon the theme has for a status emoji.
+fn symbolic_icon_name(glyph: &str) -> Option<&'static str> {
+    let candidates: &[&'static str] = match glyph {
+        "🔐" => &["dialog-password-symbolic"],
+        "👆" => &["auth-fingerprint-symbolic", "fingerprint-symbolic"],
+        "🔑" => &["auth-sim-symbolic", "security-high-symbolic"],
+        "🔢" => &["input-dialpad-symbolic"],
+        "💳" => &["auth-smartcard-symbolic", "media-flash-symbolic"],
+        "📷" => &["camera-web-symbolic", "camera-photo-symbolic"],
+        "❌" => &["dialog-error-symbolic"],
+        "⚠️" => &["dialog-warning-symbolic"],
+        "⏱" => &["alarm-symbolic", "preferences-system-time-symbolic"],
+        "⏳" => &["content-loading-symbolic", "emblem-synchronizing-symbolic"],
+        "🔒" => &["system-lock-screen-symbolic", "changes-prevent-symbolic"],
+        "✅" => &["object-select-symbolic", "emblem-ok-symbolic"],
+        _ => &[],
+    };
+    let display = gtk4::gdk::Display::default()?;
+    let theme = gtk4::IconTheme::for_display(&display);
+    candidates.iter().copied().find(|name| theme.has_icon(name))
+}
+
+/// Time left on pam_fprintd's current try, as a thin bar under the status.
+struct FingerprintCountdown {
+    bar: gtk4::ProgressBar,
+    /// Bumped on every start and stop, so a superseded tick stops itself.
+    generation: Cell<u64>,
+    /// The `biometric-hints` setting.
+    enabled: Cell<bool>,
+}
+
+impl FingerprintCountdown {
+    /// Count down a fresh try from the full timeout.
+    fn start(self: &Rc<Self>) {
+        if !self.enabled.get() {
+            return;
+        }
+        let generation = self.generation.get() + 1;
+        self.generation.set(generation);
+        let started = std::time::Instant::now();
+        self.bar.set_fraction(1.0);
+        self.bar.set_visible(true);
+        let weak = Rc::downgrade(self);
+        glib::timeout_add_local(std::time::Duration::from_millis(100), move || {
+            let Some(countdown) = weak.upgrade() else {
+                return glib::ControlFlow::Break;
+            };
+            if countdown.generation.get() != generation {
+                return glib::ControlFlow::Break;
+            }
+            let left = FPRINTD_TIMEOUT.saturating_sub(started.elapsed());
+            if left.is_zero() {
+                countdown.stop();
+                return glib::ControlFlow::Break;
+            }
+            countdown
+                .bar
+                .set_fraction(left.as_secs_f64() / FPRINTD_TIMEOUT.as_secs_f64());
+            glib::ControlFlow::Continue
+        });
+    }
+
+    fn stop(&self) {
+        self.generation.set(self.generation.get() + 1);
+        self.bar.set_visible(false);
+    }
+}
+
+/// The dialog's template children that `setup_ui` drives directly, and the
+/// helpers built around them.
 struct Widgets {
-    message_label: gtk4::Label,
-    fingerprint_label: gtk4::Label,
+    details_expander: gtk4::Expander,
+    details_grid: gtk4::Grid,
+    fingerprint_icon: StatusIcon,
     fingerprint_status: gtk4::Label,
+    fingerprint_countdown: Rc<FingerprintCountdown>,
+    status_lines: Rc<StatusLines>,
+    answered_prompts: Rc<AnsweredPrompts>,
+    retry_button: gtk4::Button,
+    fingerprint_again_button: gtk4::Button,
     separator_label: gtk4::Label,
-    user_box: gtk4::Box,
+    more_options_button: gtk4::Button,
+    user_revealer: gtk4::Revealer,
     user_dropdown: gtk4::DropDown,
+    user_entry_box: gtk4::Box,
+    user_entry: gtk4::Entry,
     password_box: gtk4::Box,
+    password_label: gtk4::Label,
     password_entry: gtk4::PasswordEntry,
-    cancel_button: gtk4::Button,
+    pin_pad: gtk4::Grid,
+    report_button: gtk4::Button,
     auth_button: gtk4::Button,
 }
 
-fn build_window(app: &gtk4::Application) -> (gtk4::Window, Widgets) {
-    let window = gtk4::Window::builder()
-        .application(app)
-        .title("Authentication Required")
-        .default_width(380)
-        .resizable(false)
-        .modal(true)
-        .build();
+/// `icon_name` if the icon theme has it, else the generic password icon.
+fn themed_icon_name(icon_name: &str) -> &str {
+    let available = !icon_name.is_empty()
+        && gtk4::gdk::Display::default()
+            .is_some_and(|display| gtk4::IconTheme::for_display(&display).has_icon(icon_name));
+    if available {
+        icon_name
+    } else {
+        DEFAULT_ICON_NAME
+    }
+}
 
-    let main_box = gtk4::Box::builder()
-        .orientation(gtk4::Orientation::Vertical)
-        .spacing(8)
-        .margin_top(24)
-        .margin_bottom(24)
-        .margin_start(24)
-        .margin_end(24)
-        .build();
+/// Fill the details table; the expander is hidden when there is nothing to show.
+fn show_details(expander: &gtk4::Expander, grid: &gtk4::Grid, details: &[(String, String)]) {
+    while let Some(child) = grid.first_child() {
+        grid.remove(&child);
+    }
+    fill_details(grid, details);
+    // The pane also holds "Report a problem", so it stays even when empty.
+    grid.set_visible(!details.is_empty());
+    expander.set_expanded(false);
+}
 
-    let header_label = gtk4::Label::builder()
-        .label("Authentication Required")
-        .halign(gtk4::Align::Center)
-        .build();
-    header_label.add_css_class("auth-header");
+#[cfg(not(feature = "adwaita"))]
+fn fill_details(grid: &gtk4::Grid, details: &[(String, String)]) {
+    for (row, (label, value)) in details.iter().enumerate() {
+        let key = gtk4::Label::builder()
+            .label(label.as_str())
+            .xalign(1.0)
+            .valign(gtk4::Align::Start)
+            .build();
+        key.add_css_class("dim-label");
+        let value = gtk4::Label::builder()
+            .label(value.as_str())
+            .xalign(0.0)
+            .wrap(true)
+            .wrap_mode(gtk4::pango::WrapMode::WordChar)
+            .selectable(true)
+            .hexpand(true)
+            .build();
+        grid.attach(&key, 0, row as i32, 1, 1);
+        grid.attach(&value, 1, row as i32, 1, 1);
+    }
+}
 
-    let message_label = gtk4::Label::builder()
-        .label("")
-        .wrap(true)
-        .halign(gtk4::Align::Center)
+/// Adwaita lays the details out as a boxed list of property rows instead.
+#[cfg(feature = "adwaita")]
+fn fill_details(grid: &gtk4::Grid, details: &[(String, String)]) {
+    let list = gtk4::ListBox::builder()
+        .selection_mode(gtk4::SelectionMode::None)
+        .hexpand(true)
         .build();
-    message_label.add_css_class("auth-message");
+    list.add_css_class("boxed-list");
+    for (label, value) in details {
+        let row = adw::ActionRow::builder()
+            .title(glib::markup_escape_text(label))
+            .subtitle(glib::markup_escape_text(value))
+            .subtitle_selectable(true)
+            .build();
+        row.add_css_class("property");
+        list.append(&row);
+    }
+    grid.attach(&list, 0, 0, 1, 1);
+}
 
-    let fingerprint_frame = gtk4::Box::builder()
-        .orientation(gtk4::Orientation::Vertical)
-        .halign(gtk4::Align::Center)
-        .build();
-    fingerprint_frame.add_css_class("fingerprint-frame");
+/// Show a PAM message in its method's status line. pam_fprintd's messages
+/// get a matching, animated icon, failed matches the number of tries left,
+/// and each try a countdown of the time left to touch the reader. Howdy's
+/// failures only mean face unlock gave up, and a pulled smartcard only
+/// needs to go back in, so both are shown as plain status.
+///
+/// Returns whether pam_fprintd gave up on this conversation, out of tries
+/// or time, so PAM will move on without it.
+fn show_pam_message(
+    status_lines: &StatusLines,
+    icon: &StatusIcon,
+    countdown: &Rc<FingerprintCountdown>,
+    fingerprint_failures: &Cell<u32>,
+    text: &str,
+    is_error: bool,
+) -> bool {
+    let method = AuthMethod::classify_during(text, status_lines.has(AuthMethod::Face));
+    if method == AuthMethod::Face || AuthMethod::is_recoverable(text) {
+        icon.set_visible(true);
+        icon.set_glyph(method.icon().unwrap_or("👆"));
+        status_lines.show(method, text, false);
+        return false;
+    }
+    if method != AuthMethod::Other {
+        icon.set_visible(true);
+    }
+    let state = (method == AuthMethod::Fingerprint)
+        .then(|| FingerprintState::parse(text))
+        .flatten();
+    let Some(state) = state else {
+        if is_error {
+            status_lines.show_error(method, text);
+            icon.set_glyph("❌");
+        } else {
+            status_lines.show(method, text, false);
+            if let Some(glyph) = method.icon() {
+                icon.set_glyph(glyph);
+            }
+        }
+        return false;
+    };
 
-    let fingerprint_label = gtk4::Label::builder()
-        .label("🔐")
-        .halign(gtk4::Align::Center)
-        .build();
-    fingerprint_label.add_css_class("fingerprint-label");
+    icon.set_glyph(state.icon());
+    match state {
+        FingerprintState::Waiting => countdown.start(),
+        // A smudged scan doesn't restart pam_fprintd's timer.
+        FingerprintState::Retry => {}
+        _ => countdown.stop(),
+    }
+    match state {
+        FingerprintState::Waiting | FingerprintState::Retry => icon.set_scanning(true),
+        FingerprintState::NoMatch => icon.play("fingerprint-shake", SHAKE_DURATION),
+        FingerprintState::TimedOut | FingerprintState::Unavailable => {}
+    }
+    let mut text = text.to_owned();
+    let mut gave_up = state == FingerprintState::TimedOut;
+    if state == FingerprintState::NoMatch {
+        let failures = fingerprint_failures.get() + 1;
+        fingerprint_failures.set(failures);
+        let left = FPRINTD_MAX_TRIES.saturating_sub(failures);
+        if left > 0 {
+            let tries = if left == 1 { "try" } else { "tries" };
+            text = format!("{text} ({left} {tries} left)");
+        } else {
+            gave_up = true;
+        }
+    }
+    if is_error || state.is_error() {
+        status_lines.show_error(method, &text);
+    } else {
+        status_lines.show(method, &text, false);
+    }
+    gave_up
+}
 
-    let fingerprint_status = gtk4::Label::builder()
-        .label("Waiting for authentication...")
-        .wrap(true)
-        .halign(gtk4::Align::Center)
-        .build();
-    fingerprint_status.add_css_class("fingerprint-status");
+/// Reset the icon and status for a fresh session. The 🔐 only shows when a
+/// fingerprint reader could be in play; PAM's first message settles it.
+fn show_waiting(icon: &StatusIcon, status: &gtk4::Label, countdown: &FingerprintCountdown) {
+    countdown.stop();
+    let biometric = doctor::fprintd_installed();
+    icon.set_glyph("🔐");
+    icon.set_visible(biometric);
+    status.set_label(if biometric {
+        "Waiting for authentication..."
+    } else {
+        "Starting authentication..."
+    });
+}
 
-    fingerprint_frame.append(&fingerprint_label);
-    fingerprint_frame.append(&fingerprint_status);
+/// The text widget inside `entry`, which holds its buffer.
+fn entry_text(entry: &gtk4::PasswordEntry) -> Option<gtk4::Text> {
+    entry
+        .delegate()
+        .and_then(|delegate| delegate.downcast::<gtk4::Text>().ok())
+}
 
-    let separator_label = gtk4::Label::builder()
-        .label("— or enter password —")
-        .halign(gtk4::Align::Center)
-        .visible(false)
-        .build();
-    separator_label.add_css_class("separator-label");
+/// Length of `entry`'s text in characters, read without copying it.
+fn entry_length(entry: &gtk4::PasswordEntry) -> i32 {
+    entry_text(entry).map_or(0, |text| i32::from(text.text_length()))
+}
 
-    let user_box = gtk4::Box::builder()
-        .orientation(gtk4::Orientation::Horizontal)
-        .spacing(12)
-        .margin_top(8)
-        .build();
+/// The secret typed into `entry`, copied straight from its buffer. `text()`
+/// would hand back an intermediate copy that is freed without being wiped.
+fn entry_secret(entry: &gtk4::PasswordEntry) -> SecretString {
+    let editable = entry.upcast_ref::<gtk4::Editable>();
+    // SAFETY: the string is owned by the entry's buffer and is copied out
+    // before control returns to GTK, which is the only thing that changes it.
+    let text = unsafe {
+        std::ffi::CStr::from_ptr(gtk4::ffi::gtk_editable_get_text(editable.to_glib_none().0))
+    };
+    SecretString::from(text.to_string_lossy().into_owned())
+}
 
-    let user_label = gtk4::Label::builder()
-        .label("User:")
-        .width_chars(10)
-        .xalign(0.0)
-        .build();
+/// Set the entry up for a secret, or for an echo-on prompt such as a
+/// one-time code, which is shown as typed. Either way it is labelled with
+/// PAM's own prompt ("Password:", "PIN for token:", ...).
+fn set_echo(label: &gtk4::Label, entry: &gtk4::PasswordEntry, prompt: &str, echo_on: bool) {
+    if let Some(text) = entry_text(entry) {
+        text.set_visibility(echo_on);
+    }
+    entry.set_show_peek_icon(!echo_on);
 
-    let user_dropdown = gtk4::DropDown::from_strings(&[]);
-    user_dropdown.set_hexpand(true);
+    let prompt = match prompt.trim() {
+        "" if echo_on => "Code:",
+        "" => "Password:",
+        prompt => prompt,
+    };
+    label.set_label(prompt);
+    entry.set_placeholder_text(Some(prompt.trim_end_matches(':').trim_end()));
+}
 
-    user_box.append(&user_label);
-    user_box.append(&user_dropdown);
+/// A standalone window explaining why something went wrong, e.g. why a
+/// request went unanswered, so the program that asked doesn't just appear
+/// to hang.
+fn show_error(app: Option<&gtk4::Application>, title: &str, text: &str) {
+    let window = gtk4::Window::builder()
+        .title(title)
+        .default_width(380)
+        .resizable(false)
+        .build();
+    window.set_application(app);
 
-    let password_box = gtk4::Box::builder()
-        .orientation(gtk4::Orientation::Horizontal)
+    let main_box = gtk4::Box::builder()
+        .orientation(gtk4::Orientation::Vertical)
         .spacing(12)
-        .margin_top(4)
-        .visible(false)
+        .margin_top(24)
+        .margin_bottom(24)
+        .margin_start(24)
+        .margin_end(24)
         .build();
-
-    let password_label = gtk4::Label::builder()
-        .label("Password:")
-        .width_chars(10)
+    let label = gtk4::Label::builder()
+        .label(text)
+        .wrap(true)
         .xalign(0.0)
         .build();
+    main_box.append(&label);
 
-    let password_entry = gtk4::PasswordEntry::builder()
-        .placeholder_text("Enter password")
-        .show_peek_icon(true)
-        .sensitive(false)
-        .hexpand(true)
+    let close_button = gtk4::Button::builder()
+        .label("Close")
+        .halign(gtk4::Align::End)
         .build();
+    main_box.append(&close_button);
+    window.set_child(Some(&main_box));
+    window.set_default_widget(Some(&close_button));
+
+    {
+        let window_c = window.clone();
+        close_button.connect_clicked(move |_| window_c.close());
+    }
+    window.present();
+}
+
+/// Keys for the on-screen keypad for PIN prompts, for touch screens and
+/// kiosks. Types into `entry`; ✓ submits through `submit`.
+fn fill_pin_pad(grid: &gtk4::Grid, entry: &gtk4::PasswordEntry, submit: &gtk4::Button) {
+    let keys = ["1", "2", "3", "4", "5", "6", "7", "8", "9", "⌫", "0", "✓"];
+    for (index, key) in keys.into_iter().enumerate() {
+        let button = gtk4::Button::with_label(key);
+        let entry = entry.clone();
+        match key {
+            "⌫" => {
+                button.connect_clicked(move |_| {
+                    let end = entry_length(&entry);
+                    if end > 0 {
+                        entry.delete_text(end - 1, end);
+                    }
+                });
+            }
+            "✓" => {
+                button.add_css_class("suggested-action");
+                let submit = submit.clone();
+                button.connect_clicked(move |_| submit.emit_clicked());
+            }
+            digit => {
+                // Typed in place: going through `text()` would leave a
+                // copy of the PIN behind on every key.
+                button.connect_clicked(move |_| {
+                    let mut end = entry_length(&entry);
+                    entry.insert_text(digit, &mut end);
+                });
+            }
+        }
+        grid.attach(&button, (index % 3) as i32, (index / 3) as i32, 1, 1);
+    }
+}
 
-    password_box.append(&password_label);
-    password_box.append(&password_entry);
+fn build_window(
+    app: &gtk4::Application,
+    settings: Option<&gio::Settings>,
+) -> (BadgedAuthDialog, Widgets) {
+    let window = BadgedAuthDialog::new(app, DEFAULT_ICON_NAME);
+    if let Some(settings) = settings {
+        settings
+            .bind("window-width", &window, "default-width")
+            .get()
+            .build();
+    }
+    let parts = window.content().imp();
 
-    let button_box = gtk4::Box::builder()
-        .orientation(gtk4::Orientation::Horizontal)
-        .spacing(8)
-        .halign(gtk4::Align::End)
-        .margin_top(16)
-        .build();
+    let fingerprint_icon = StatusIcon {
+        container: parts.status_icon.get(),
+        image: parts.status_icon_image.get(),
+        label: parts.status_icon_label.get(),
+    };
+    fingerprint_icon.set_glyph("🔐");
 
-    let cancel_button = gtk4::Button::with_label("Cancel");
-    let auth_button = gtk4::Button::with_label("Authenticate");
-    auth_button.add_css_class("suggested-action");
-    auth_button.set_sensitive(false);
+    let fingerprint_countdown = Rc::new(FingerprintCountdown {
+        bar: parts.fingerprint_countdown.get(),
+        generation: Cell::new(0),
+        enabled: Cell::new(true),
+    });
 
-    button_box.append(&cancel_button);
-    button_box.append(&auth_button);
+    let status_lines = Rc::new(StatusLines {
+        primary: parts.fingerprint_status.get(),
+        extra: parts.extra_status.get(),
+        lines: RefCell::new(Vec::new()),
+        pinned: RefCell::new(Vec::new()),
+    });
 
-    main_box.append(&header_label);
-    main_box.append(&message_label);
-    main_box.append(&fingerprint_frame);
-    main_box.append(&separator_label);
-    main_box.append(&user_box);
-    main_box.append(&password_box);
-    main_box.append(&button_box);
+    let answered_prompts = Rc::new(AnsweredPrompts {
+        container: parts.answered_prompts.get(),
+    });
 
-    window.set_child(Some(&main_box));
+    fill_pin_pad(&parts.pin_pad, &parts.password_entry, &parts.auth_button);
 
     let widgets = Widgets {
-        message_label,
-        fingerprint_label,
-        fingerprint_status,
-        separator_label,
-        user_box,
-        user_dropdown,
-        password_box,
-        password_entry,
-        cancel_button,
-        auth_button,
+        details_expander: parts.details_expander.get(),
+        details_grid: parts.details_grid.get(),
+        fingerprint_icon,
+        fingerprint_status: parts.fingerprint_status.get(),
+        fingerprint_countdown,
+        status_lines,
+        answered_prompts,
+        retry_button: parts.retry_button.get(),
+        fingerprint_again_button: parts.fingerprint_again_button.get(),
+        separator_label: parts.separator_label.get(),
+        more_options_button: parts.more_options_button.get(),
+        user_revealer: parts.user_revealer.get(),
+        user_dropdown: parts.user_dropdown.get(),
+        user_entry_box: parts.user_entry_box.get(),
+        user_entry: parts.user_entry.get(),
+        password_box: parts.password_box.get(),
+        password_label: parts.password_label.get(),
+        password_entry: parts.password_entry.get(),
+        pin_pad: parts.pin_pad.get(),
+        report_button: parts.report_button.get(),
+        auth_button: parts.auth_button.get(),
     };
 
     (window, widgets)
 }
 
-fn setup_ui(window: gtk4::Window, widgets: Widgets, channels: UiChannels) {
+fn setup_ui(
+    window: BadgedAuthDialog,
+    widgets: Widgets,
+    channels: UiChannels,
+    settings: Option<gio::Settings>,
+) {
     let UiChannels { event_rx, shared } = channels;
     let users: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
     let initializing: Rc<RefCell<bool>> = Rc::new(RefCell::new(false));
     let current_request_id: Rc<RefCell<Option<u64>>> = Rc::new(RefCell::new(None));
+    let current_prompt_id = Rc::new(Cell::new(0u64));
+    let fingerprint_failures = Rc::new(Cell::new(0u32));
+    let external_prompt: Rc<RefCell<Option<(u64, u64, ExternalPrompt)>>> =
+        Rc::new(RefCell::new(None));
+    // Results from prompt programs, tagged with the request and prompt they answer.
+    let (prompt_result_tx, prompt_result_rx) = async_channel::unbounded();
 
     let Widgets {
-        message_label,
-        fingerprint_label,
+        details_expander,
+        details_grid,
+        fingerprint_icon,
         fingerprint_status,
+        fingerprint_countdown,
+        status_lines,
+        answered_prompts,
+        retry_button,
+        fingerprint_again_button,
         separator_label,
-        user_box,
+        more_options_button,
+        user_revealer,
         user_dropdown,
+        user_entry_box,
+        user_entry,
         password_box,
+        password_label,
         password_entry,
-        cancel_button,
+        pin_pad,
+        report_button,
         auth_button,
     } = widgets;
 
-    // Poll listener events every 50ms.
+    // Handle listener events as they arrive.
     let window_c = window.clone();
-    let message_label_c = message_label.clone();
-    let fingerprint_label_c = fingerprint_label.clone();
+    let details_expander_c = details_expander.clone();
+    let details_grid_c = details_grid.clone();
+    let fingerprint_icon_c = fingerprint_icon.clone();
     let fingerprint_status_c = fingerprint_status.clone();
+    let fingerprint_countdown_c = fingerprint_countdown.clone();
+    let status_lines_c = status_lines.clone();
+    let answered_prompts_c = answered_prompts.clone();
+    let retry_button_c = retry_button.clone();
+    let fingerprint_again_button_c = fingerprint_again_button.clone();
     let separator_label_c = separator_label.clone();
-    let user_box_c = user_box.clone();
+    let more_options_button_c = more_options_button.clone();
+    let user_revealer_c = user_revealer.clone();
     let user_dropdown_c = user_dropdown.clone();
+    let user_entry_box_c = user_entry_box.clone();
+    let user_entry_c = user_entry.clone();
     let password_box_c = password_box.clone();
+    let password_label_c = password_label.clone();
     let password_entry_c = password_entry.clone();
+    let pin_pad_c = pin_pad.clone();
     let auth_button_c = auth_button.clone();
-    let shared_events = Rc::clone(&shared);
     let users_c = users.clone();
     let initializing_c = initializing.clone();
     let current_request_id_c = current_request_id.clone();
+    let external_prompt_c = external_prompt.clone();
+    let current_prompt_id_c = current_prompt_id.clone();
+    let fingerprint_failures_c = fingerprint_failures.clone();
+    let shared_prompt = shared.clone();
+    let settings_c = settings.clone();
 
-    glib::timeout_add_local(std::time::Duration::from_millis(50), move || {
-        while let Ok(event) = event_rx.try_recv() {
+    // The `theme` preset goes above the base and dark styles.
+    let theme_provider = gtk4::CssProvider::new();
+    theme_provider.load_from_data(shared.config().theme.css());
+    gtk4::style_context_add_provider_for_display(
+        &WidgetExt::display(&window),
+        &theme_provider,
+        gtk4::STYLE_PROVIDER_PRIORITY_APPLICATION + 2,
+    );
+
+    if let Some(settings) = &settings {
+        fingerprint_countdown
+            .enabled
+            .set(settings.boolean("biometric-hints"));
+        let fingerprint_countdown_c = fingerprint_countdown.clone();
+        settings.connect_changed(Some("biometric-hints"), move |settings, key| {
+            fingerprint_countdown_c.enabled.set(settings.boolean(key));
+        });
+    }
+
+    glib::spawn_future_local(async move {
+        while let Ok(event) = event_rx.recv().await {
+            // Any sign of life from the helper retires the watchdog's offer.
+            if !matches!(event, UiEvent::HelperStalled | UiEvent::ThemeChanged(_)) {
+                retry_button_c.set_visible(false);
+            }
             match event {
                 UiEvent::ShowDialog {
                     request_id,
                     message,
+                    icon_name,
                     users,
+                    selected_user,
+                    details,
+                    requested_by,
+                    ..
                 } => {
-                    eprintln!("[ui] ShowDialog: {message}");
+                    log!("[ui] ShowDialog: {message}");
                     *current_request_id_c.borrow_mut() = Some(request_id);
                     *initializing_c.borrow_mut() = true;
                     *users_c.borrow_mut() = users.clone();
-                    message_label_c.set_label(&message);
-                    fingerprint_label_c.set_label("🔐");
-                    fingerprint_status_c.set_label("Waiting for authentication...");
+                    window_c.set_message(message.as_str());
+                    window_c.set_error(None::<&str>);
+                    show_details(&details_expander_c, &details_grid_c, &details);
+                    if settings_c
+                        .as_ref()
+                        .is_some_and(|settings| settings.boolean("show-details"))
+                    {
+                        details_expander_c.set_expanded(true);
+                    }
+                    window_c.set_requested_by(requested_by.as_deref());
+                    window_c.set_action_icon(themed_icon_name(&icon_name));
+                    fingerprint_failures_c.set(0);
+                    fingerprint_again_button_c.set_visible(false);
+                    status_lines_c.reset();
+                    answered_prompts_c.clear();
+                    show_waiting(
+                        &fingerprint_icon_c,
+                        &fingerprint_status_c,
+                        &fingerprint_countdown_c,
+                    );
                     fingerprint_status_c.remove_css_class("error");
                     fingerprint_status_c.remove_css_class("success");
                     let user_refs: Vec<&str> = users.iter().map(|user| user.as_str()).collect();
                     let user_model = gtk4::StringList::new(&user_refs);
                     user_dropdown_c.set_model(Some(&user_model));
-                    user_dropdown_c.set_selected(0);
+                    user_dropdown_c.set_selected(selected_user as u32);
+                    user_dropdown_c.set_sensitive(true);
                     separator_label_c.set_visible(false);
                     password_box_c.set_visible(false);
+                    pin_pad_c.set_visible(false);
                     password_entry_c.set_text("");
                     password_entry_c.set_sensitive(false);
                     auth_button_c.set_sensitive(false);
-                    user_box_c.set_visible(users.len() > 1);
+                    let collapsed = users.len() > 1
+                        && users.get(selected_user).map(String::as_str)
+                            == glib::user_name().to_str();
+                    user_revealer_c.set_visible(users.len() > 1);
+                    user_revealer_c.set_reveal_child(users.len() > 1 && !collapsed);
+                    more_options_button_c.set_visible(collapsed);
+                    user_entry_box_c.set_visible(users.is_empty());
+                    user_entry_c.set_text("");
+                    user_entry_c.set_sensitive(true);
                     *initializing_c.borrow_mut() = false;
                     window_c.present();
+                    if users.is_empty() {
+                        fingerprint_status_c.set_label("Enter the user to authenticate as");
+                        user_entry_c.grab_focus();
+                    }
                 }
                 UiEvent::PamInfo(text) => {
-                    eprintln!("[ui] PamInfo: {text}");
-                    fingerprint_status_c.set_label(&text);
-                    fingerprint_label_c.set_label("👆");
-                    fingerprint_status_c.remove_css_class("error");
-                    fingerprint_status_c.remove_css_class("success");
+                    log!("[ui] PamInfo: {text}");
+                    let gave_up = show_pam_message(
+                        &status_lines_c,
+                        &fingerprint_icon_c,
+                        &fingerprint_countdown_c,
+                        &fingerprint_failures_c,
+                        &text,
+                        false,
+                    );
+                    if gave_up && fingerprint_countdown_c.enabled.get() {
+                        fingerprint_again_button_c.set_visible(true);
+                    }
                 }
                 UiEvent::PamError(text) => {
-                    eprintln!("[ui] PamError: {text}");
-                    fingerprint_status_c.set_label(&text);
-                    fingerprint_label_c.set_label("❌");
-                    fingerprint_status_c.add_css_class("error");
-                    fingerprint_status_c.remove_css_class("success");
+                    log!("[ui] PamError: {text}");
+                    let gave_up = show_pam_message(
+                        &status_lines_c,
+                        &fingerprint_icon_c,
+                        &fingerprint_countdown_c,
+                        &fingerprint_failures_c,
+                        &text,
+                        true,
+                    );
+                    if gave_up && fingerprint_countdown_c.enabled.get() {
+                        fingerprint_again_button_c.set_visible(true);
+                    }
                 }
-                UiEvent::PasswordNeeded => {
-                    eprintln!("[ui] PasswordNeeded");
-                    separator_label_c.set_visible(true);
+                UiEvent::PasswordNeeded {
+                    prompt_id,
+                    prompt,
+                    echo_on,
+                } => {
+                    log!("[ui] PasswordNeeded #{prompt_id}: {prompt:?} (echo_on={echo_on})");
+                    current_prompt_id_c.set(prompt_id);
+                    // PAM moved on from the fingerprint module.
+                    fingerprint_countdown_c.stop();
+                    // A chained prompt (password, then a one-time code) must
+                    // not inherit the previous answer or its status; the
+                    // answer stays on a row of its own under its prompt.
+                    if password_box_c.is_visible()
+                        && entry_text(&password_entry_c).is_some_and(|text| text.text_length() > 0)
+                    {
+                        answered_prompts_c.keep(&password_label_c, &password_entry_c);
+                    }
+                    password_entry_c.set_text("");
+                    if fingerprint_status_c.label() == "Authenticating..." {
+                        fingerprint_status_c.set_label("One more step: answer the next prompt");
+                    }
+                    if let Some(glyph) = AuthMethod::classify(&prompt).icon() {
+                        fingerprint_icon_c.set_glyph(glyph);
+                        fingerprint_icon_c.set_visible(true);
+                    }
+                    user_dropdown_c.set_sensitive(true);
+                    user_entry_c.set_sensitive(true);
+                    let config = shared_prompt.config();
+                    if let (Some(prompt_config), Some(request_id)) =
+                        (config.prompt.as_ref(), *current_request_id_c.borrow())
+                    {
+                        let user = users_c
+                            .borrow()
+                            .get(user_dropdown_c.selected() as usize)
+                            .cloned()
+                            .unwrap_or_default();
+                        match ExternalPrompt::spawn(
+                            prompt_config,
+                            &user,
+                            &window_c.message(),
+                            &prompt,
+                        ) {
+                            Ok(prompt) => {
+                                let result = prompt.result();
+                                let prompt_result_tx = prompt_result_tx.clone();
+                                glib::spawn_future_local(async move {
+                                    if let Some(result) = result.await {
+                                        let _ = prompt_result_tx
+                                            .send((request_id, prompt_id, result))
+                                            .await;
+                                    }
+                                });
+                                *external_prompt_c.borrow_mut() =
+                                    Some((request_id, prompt_id, prompt));
+                                fingerprint_status_c.set_label("Waiting for the prompt program...");
+                                continue;
+                            }
+                            Err(err) => log!("[ui] External prompt failed: {err}"),
+                        }
+                    }
+                    set_echo(&password_label_c, &password_entry_c, &prompt, echo_on);
+                    // PAM went straight to the prompt: no biometric step.
+                    let biometric = status_lines_c.has_biometric();
+                    if !biometric && fingerprint_icon_c.glyph() == "🔐" {
+                        fingerprint_icon_c.set_visible(false);
+                        fingerprint_status_c.set_label("");
+                    }
+                    separator_label_c.set_visible(biometric);
+                    pin_pad_c.set_visible(config.pin_pad && pam::is_pin_prompt(&prompt));
                     password_box_c.set_visible(true);
                     password_entry_c.set_sensitive(true);
                     password_entry_c.grab_focus();
                     auth_button_c.set_sensitive(true);
                 }
+                UiEvent::AuthFailed => {
+                    log!("[ui] AuthFailed");
+                    // pam_fprintd counts tries per conversation.
+                    fingerprint_failures_c.set(0);
+                    fingerprint_again_button_c.set_visible(false);
+                    fingerprint_countdown_c.stop();
+                    user_dropdown_c.set_sensitive(true);
+                    user_entry_c.set_sensitive(true);
+                    fingerprint_icon_c.set_glyph("❌");
+                    fingerprint_icon_c.set_visible(true);
+                    fingerprint_status_c.set_label("Authentication failed, please try again");
+                    window_c.set_error(Some("Authentication failed, please try again"));
+                    status_lines_c.clear_pinned();
+                    answered_prompts_c.clear();
+                    fingerprint_status_c.add_css_class("error");
+                    fingerprint_status_c.remove_css_class("success");
+                    password_entry_c.set_text("");
+                    password_entry_c.set_sensitive(false);
+                    pin_pad_c.set_visible(false);
+                    auth_button_c.set_sensitive(false);
+                    // The default identity didn't work; offer the others.
+                    if user_revealer_c.is_visible() {
+                        user_revealer_c.set_reveal_child(true);
+                        more_options_button_c.set_visible(false);
+                    }
+                }
+                UiEvent::LockedOut { seconds } => {
+                    log!("[ui] LockedOut: {seconds}s");
+                    window_c.set_error(Some("Too many failed attempts"));
+                    user_dropdown_c.set_sensitive(false);
+                    user_entry_c.set_sensitive(false);
+                    fingerprint_icon_c.set_glyph("🔒");
+                    fingerprint_icon_c.set_visible(true);
+                    let request_id = *current_request_id_c.borrow();
+                    let deadline =
+                        std::time::Instant::now() + std::time::Duration::from_secs(seconds);
+                    let status = fingerprint_status_c.clone();
+                    let current_request_id_c = current_request_id_c.clone();
+                    let tick = move || {
+                        let left = deadline.saturating_duration_since(std::time::Instant::now());
+                        if left.is_zero() || *current_request_id_c.borrow() != request_id {
+                            return glib::ControlFlow::Break;
+                        }
+                        status.set_label(&format!(
+                            "Too many failed attempts. Try again in {}s",
+                            left.as_secs() + 1
+                        ));
+                        glib::ControlFlow::Continue
+                    };
+                    tick();
+                    glib::timeout_add_local(std::time::Duration::from_secs(1), tick);
+                }
+                UiEvent::HelperStalled => {
+                    log!("[ui] HelperStalled");
+                    fingerprint_icon_c.set_glyph("⏳");
+                    fingerprint_icon_c.set_visible(true);
+                    fingerprint_status_c.set_label("Still waiting on the authentication service");
+                    fingerprint_status_c.remove_css_class("success");
+                    fingerprint_status_c.remove_css_class("error");
+                    retry_button_c.set_visible(true);
+                }
                 UiEvent::AuthComplete { success } => {
-                    eprintln!("[ui] AuthComplete: {success}");
+                    log!("[ui] AuthComplete: {success}");
+                    window_c.set_error(None::<&str>);
+                    fingerprint_again_button_c.set_visible(false);
+                    fingerprint_countdown_c.stop();
                     password_entry_c.set_text("");
                     password_entry_c.set_sensitive(false);
                     auth_button_c.set_sensitive(false);
                     if success {
-                        fingerprint_label_c.set_label("✅");
+                        fingerprint_icon_c.set_glyph("✅");
+                        fingerprint_icon_c.set_visible(true);
+                        fingerprint_icon_c.play("fingerprint-pop", POP_DURATION);
                         fingerprint_status_c.set_label("Authentication successful");
                         fingerprint_status_c.add_css_class("success");
                         let win = window_c.clone();
+                        let current_request_id_c = current_request_id_c.clone();
                         glib::timeout_add_local_once(
                             std::time::Duration::from_millis(300),
-                            move || win.set_visible(false),
+                            move || {
+                                // A queued request may have taken over the dialog.
+                                if current_request_id_c.borrow().is_none() {
+                                    win.set_visible(false);
+                                }
+                            },
                         );
                     } else {
+                        gtk4::prelude::GtkWindowExt::set_focus(&window_c, gtk4::Widget::NONE);
                         window_c.set_visible(false);
                     }
                     *current_request_id_c.borrow_mut() = None;
                 }
-                UiEvent::PolkitCancelled { request_id } => {
-                    if Some(request_id) == *current_request_id_c.borrow()
-                        && shared_events.cancel_request(request_id)
-                    {
+                UiEvent::RequestFailed { request_id, reason } => {
+                    log!("[ui] RequestFailed: {reason}");
+                    // Requests rejected on arrival never had the dialog.
+                    if *current_request_id_c.borrow() == Some(request_id) {
+                        fingerprint_countdown_c.stop();
                         password_entry_c.set_text("");
-                        password_entry_c.set_sensitive(false);
-                        auth_button_c.set_sensitive(false);
-                        *current_request_id_c.borrow_mut() = None;
                         gtk4::prelude::GtkWindowExt::set_focus(&window_c, gtk4::Widget::NONE);
                         window_c.set_visible(false);
+                        *current_request_id_c.borrow_mut() = None;
                     }
+                    show_error(
+                        window_c.application().as_ref(),
+                        "Authentication Failed",
+                        &reason,
+                    );
+                }
+                UiEvent::ThemeChanged(theme) => {
+                    log!("[ui] ThemeChanged: {}", theme.name());
+                    theme_provider.load_from_data(theme.css());
                 }
+                UiEvent::ActionDenied { action_id } => {
+                    log!("[ui] ActionDenied: {action_id}");
+                    if let Some(app) = window_c.application() {
+                        let notification = gio::Notification::new("Authentication denied");
+                        notification
+                            .set_body(Some(&format!("{action_id} is not allowed on this machine")));
+                        app.send_notification(Some("denied"), &notification);
+                    }
+                }
+            }
+
+            // A prompt program outliving its request is dropped, which kills it.
+            let stale = matches!(
+                external_prompt_c.borrow().as_ref(),
+                Some((request_id, ..)) if Some(*request_id) != *current_request_id_c.borrow()
+            );
+            if stale {
+                external_prompt_c.borrow_mut().take();
             }
         }
-        glib::ControlFlow::Continue
     });
 
+    // Act on the external prompt program's answer.
+    {
+        let shared_c = shared.clone();
+        let current_request_id_c = current_request_id.clone();
+        let external_prompt_c = external_prompt;
+        let fingerprint_status_c = fingerprint_status.clone();
+        let separator_label_c = separator_label.clone();
+        let password_box_c = password_box.clone();
+        let password_entry_c = password_entry.clone();
+        let auth_button_c = auth_button.clone();
+        let window_c = window.clone();
+        let user_dropdown_c = user_dropdown.clone();
+        glib::spawn_future_local(async move {
+            while let Ok((request_id, prompt_id, result)) = prompt_result_rx.recv().await {
+                // Ignore programs that were replaced or killed in the meantime.
+                let live = matches!(
+                    external_prompt_c.borrow().as_ref(),
+                    Some((live_request, live_prompt, _))
+                        if *live_request == request_id && *live_prompt == prompt_id
+                );
+                if !live {
+                    continue;
+                }
+                external_prompt_c.borrow_mut().take();
+                if Some(request_id) != *current_request_id_c.borrow() {
+                    continue;
+                }
+
+                match result {
+                    PromptResult::Secret(secret) => {
+                        if shared_c.respond(request_id, prompt_id, &secret) {
+                            user_dropdown_c.set_sensitive(false);
+                            fingerprint_status_c.set_label("Authenticating...");
+                        }
+                    }
+                    PromptResult::Cancelled => window_c.emit_cancel(),
+                    PromptResult::Failed(err) => {
+                        log!("[ui] External prompt failed: {err}");
+                        separator_label_c.set_visible(true);
+                        password_box_c.set_visible(true);
+                        password_entry_c.set_sensitive(true);
+                        password_entry_c.grab_focus();
+                        auth_button_c.set_sensitive(true);
+                    }
+                }
+            }
+        });
+    }
+
     // Authenticate button — submit password to the current PAM session.
     {
         let shared_c = shared.clone();
         let current_request_id_c = current_request_id.clone();
+        let current_prompt_id_c = current_prompt_id.clone();
         let password_entry_c = password_entry.clone();
+        let user_dropdown_c = user_dropdown.clone();
+        let user_entry_c = user_entry.clone();
         let fingerprint_status_c = fingerprint_status.clone();
-        auth_button.connect_clicked(move |btn| {
+        let auth_button_c = auth_button.clone();
+        window.connect_authenticate(move |window| {
             let Some(request_id) = *current_request_id_c.borrow() else {
                 return;
             };
-            let password = password_entry_c.text().to_string();
-            if shared_c.respond(request_id, &password) {
+            let password = entry_secret(&password_entry_c);
+            if shared_c.respond(request_id, current_prompt_id_c.get(), &password) {
                 password_entry_c.set_sensitive(false);
-                btn.set_sensitive(false);
+                user_dropdown_c.set_sensitive(false);
+                user_entry_c.set_sensitive(false);
+                auth_button_c.set_sensitive(false);
+                window.set_error(None::<&str>);
                 fingerprint_status_c.set_label("Authenticating...");
             }
         });
     }
 
-    // Enter key on password field triggers auth button.
+    // Cancel button — cancel the current PAM session.
+    {
+        let shared_c = shared.clone();
+        let current_request_id_c = current_request_id.clone();
+        window.connect_cancel(move |window| {
+            if let Some(request_id) = *current_request_id_c.borrow() {
+                let _ = shared_c.cancel_request(request_id);
+                *current_request_id_c.borrow_mut() = None;
+            }
+            gtk4::prelude::GtkWindowExt::set_focus(window, gtk4::Widget::NONE);
+            window.set_visible(false);
+        });
+    }
+
+    // Alt+Up/Down cycles identities; changing the selection goes through the
+    // same notify handler as picking one with the pointer.
+    {
+        let controller = gtk4::ShortcutController::new();
+        for (accel, step) in [("<Alt>Up", -1i64), ("<Alt>Down", 1i64)] {
+            let user_dropdown_c = user_dropdown.clone();
+            let user_revealer_c = user_revealer.clone();
+            let more_options_button_c = more_options_button.clone();
+            let action = gtk4::CallbackAction::new(move |_, _| {
+                let count = user_dropdown_c.model().map_or(0, |model| model.n_items());
+                if count < 2 || !user_dropdown_c.is_sensitive() {
+                    return glib::Propagation::Proceed;
+                }
+                user_revealer_c.set_reveal_child(true);
+                more_options_button_c.set_visible(false);
+                let next =
+                    (i64::from(user_dropdown_c.selected()) + step).rem_euclid(i64::from(count));
+                user_dropdown_c.set_selected(next as u32);
+                glib::Propagation::Stop
+            });
+            controller.add_shortcut(gtk4::Shortcut::new(
+                gtk4::ShortcutTrigger::parse_string(accel),
+                Some(action),
+            ));
+        }
+        window.add_controller(controller);
+    }
+
+    // "More options" reveals the identity selection.
+    {
+        let user_revealer_c = user_revealer.clone();
+        more_options_button.connect_clicked(move |btn| {
+            user_revealer_c.set_reveal_child(true);
+            btn.set_visible(false);
+        });
+    }
+
+    // Retry kills the stalled helper and starts a fresh one.
     {
+        let shared_c = shared.clone();
+        let current_request_id_c = current_request_id.clone();
+        let fingerprint_icon_c = fingerprint_icon.clone();
+        let fingerprint_countdown_c = fingerprint_countdown.clone();
+        let fingerprint_status_c = fingerprint_status.clone();
+        let status_lines_c = status_lines.clone();
+        let answered_prompts_c = answered_prompts.clone();
+        retry_button.connect_clicked(move |btn| {
+            btn.set_visible(false);
+            let Some(request_id) = *current_request_id_c.borrow() else {
+                return;
+            };
+            if shared_c.restart_session(request_id) {
+                status_lines_c.reset();
+                answered_prompts_c.clear();
+                show_waiting(
+                    &fingerprint_icon_c,
+                    &fingerprint_status_c,
+                    &fingerprint_countdown_c,
+                );
+            }
+        });
+    }
+
+    // Start over for the same user once pam_fprintd ran out of tries.
+    {
+        let shared_c = shared.clone();
+        let current_request_id_c = current_request_id.clone();
+        let fingerprint_icon_c = fingerprint_icon.clone();
+        let fingerprint_countdown_c = fingerprint_countdown.clone();
+        let fingerprint_status_c = fingerprint_status.clone();
+        let fingerprint_failures_c = fingerprint_failures.clone();
+        let status_lines_c = status_lines.clone();
+        let answered_prompts_c = answered_prompts.clone();
+        let separator_label_c = separator_label.clone();
+        let password_box_c = password_box.clone();
+        let password_entry_c = password_entry.clone();
+        let pin_pad_c = pin_pad.clone();
         let auth_button_c = auth_button.clone();
-        password_entry.connect_activate(move |_| {
-            if auth_button_c.is_sensitive() {
-                auth_button_c.emit_clicked();
+        fingerprint_again_button.connect_clicked(move |btn| {
+            btn.set_visible(false);
+            let Some(request_id) = *current_request_id_c.borrow() else {
+                return;
+            };
+            if shared_c.restart_session(request_id) {
+                fingerprint_failures_c.set(0);
+                status_lines_c.reset();
+                answered_prompts_c.clear();
+                separator_label_c.set_visible(false);
+                password_box_c.set_visible(false);
+                pin_pad_c.set_visible(false);
+                password_entry_c.set_text("");
+                password_entry_c.set_sensitive(false);
+                auth_button_c.set_sensitive(false);
+                show_waiting(
+                    &fingerprint_icon_c,
+                    &fingerprint_status_c,
+                    &fingerprint_countdown_c,
+                );
             }
         });
     }
 
-    // Cancel button — cancel the current PAM session.
+    // Enter in the user name field starts a session for that account.
     {
         let shared_c = shared.clone();
         let current_request_id_c = current_request_id.clone();
-        let window_c = window.clone();
-        cancel_button.connect_clicked(move |_| {
-            if let Some(request_id) = *current_request_id_c.borrow() {
-                let _ = shared_c.cancel_request(request_id);
-                *current_request_id_c.borrow_mut() = None;
+        let users_c = users.clone();
+        let initializing_c = initializing.clone();
+        let user_dropdown_c = user_dropdown.clone();
+        let separator_label_c = separator_label.clone();
+        let password_box_c = password_box.clone();
+        let password_entry_c = password_entry.clone();
+        let auth_button_c = auth_button.clone();
+        let status_lines_c = status_lines.clone();
+        let answered_prompts_c = answered_prompts.clone();
+        let fingerprint_icon_c = fingerprint_icon.clone();
+        let fingerprint_countdown_c = fingerprint_countdown.clone();
+        let fingerprint_status_c = fingerprint_status.clone();
+        user_entry.connect_activate(move |entry| {
+            let Some(request_id) = *current_request_id_c.borrow() else {
+                return;
+            };
+            let name = entry.text().trim().to_owned();
+            separator_label_c.set_visible(false);
+            password_box_c.set_visible(false);
+            password_entry_c.set_text("");
+            password_entry_c.set_sensitive(false);
+            auth_button_c.set_sensitive(false);
+            status_lines_c.reset();
+            answered_prompts_c.clear();
+            fingerprint_icon_c.set_glyph("🔐");
+            fingerprint_status_c.remove_css_class("success");
+            match shared_c.enter_user(request_id, &name) {
+                Ok(()) => {
+                    // Keep the dropdown model in step so the external prompt
+                    // and identity lookups see the typed name.
+                    *initializing_c.borrow_mut() = true;
+                    user_dropdown_c.set_model(Some(&gtk4::StringList::new(&[&name])));
+                    user_dropdown_c.set_selected(0);
+                    *initializing_c.borrow_mut() = false;
+                    *users_c.borrow_mut() = vec![name];
+                    fingerprint_status_c.remove_css_class("error");
+                    show_waiting(
+                        &fingerprint_icon_c,
+                        &fingerprint_status_c,
+                        &fingerprint_countdown_c,
+                    );
+                }
+                Err(err) => {
+                    fingerprint_icon_c.set_glyph("❌");
+                    fingerprint_icon_c.set_visible(true);
+                    fingerprint_status_c.add_css_class("error");
+                    fingerprint_status_c.set_label(&err);
+                }
             }
-            gtk4::prelude::GtkWindowExt::set_focus(&window_c, gtk4::Widget::NONE);
-            window_c.set_visible(false);
         });
     }
 
+    // "Report a problem" saves a diagnostic bundle and opens its folder.
+    // Doctor, journalctl and tar can take a while, so the bundle is built
+    // off the main loop.
+    report_button.connect_clicked(|btn| {
+        let Some(dir) = config::state_dir() else {
+            btn.set_tooltip_text(Some("Cannot locate the state directory"));
+            return;
+        };
+        btn.set_sensitive(false);
+        btn.set_tooltip_text(Some("Collecting diagnostics..."));
+        let btn = btn.clone();
+        glib::spawn_future_local(async move {
+            let output = dir.clone();
+            let result = gio::spawn_blocking(move || {
+                report::create(&output, crate::logging::file_path().as_deref())
+            })
+            .await
+            .unwrap_or_else(|_| Err(std::io::Error::other("report thread panicked")));
+            btn.set_sensitive(true);
+            match result {
+                Ok(path) => {
+                    log!("[ui] Wrote diagnostic report to {}", path.display());
+                    btn.set_tooltip_text(Some(&format!("Saved {}", path.display())));
+                    let uri = gio::File::for_path(&dir).uri();
+                    if let Err(err) =
+                        gio::AppInfo::launch_default_for_uri(&uri, gio::AppLaunchContext::NONE)
+                    {
+                        log!("[ui] Cannot open {}: {err}", dir.display());
+                    }
+                }
+                Err(err) => {
+                    log!("[ui] Failed to write diagnostic report: {err}");
+                    btn.set_tooltip_text(Some(&format!("Report failed: {err}")));
+                }
+            }
+        });
+    });
+
     // Switching the selected user restarts the session for that identity.
     {
         let shared_c = shared.clone();
@@ -427,7 +1689,10 @@ fn setup_ui(window: gtk4::Window, widgets: Widgets, channels: UiChannels) {
         let password_entry_c = password_entry.clone();
         let auth_button_c = auth_button.clone();
         let fingerprint_status_c = fingerprint_status.clone();
-        let fingerprint_label_c = fingerprint_label.clone();
+        let status_lines_c = status_lines;
+        let answered_prompts_c = answered_prompts;
+        let fingerprint_icon_c = fingerprint_icon.clone();
+        let fingerprint_countdown_c = fingerprint_countdown.clone();
         user_dropdown.connect_selected_notify(move |dropdown| {
             if *initializing_c.borrow() {
                 return;
@@ -447,10 +1712,23 @@ fn setup_ui(window: gtk4::Window, widgets: Widgets, channels: UiChannels) {
                 password_entry_c.set_text("");
                 password_entry_c.set_sensitive(false);
                 auth_button_c.set_sensitive(false);
-                fingerprint_status_c.set_label("Waiting for authentication...");
-                fingerprint_label_c.set_label("🔐");
+                status_lines_c.reset();
+                answered_prompts_c.clear();
+                show_waiting(
+                    &fingerprint_icon_c,
+                    &fingerprint_status_c,
+                    &fingerprint_countdown_c,
+                );
                 fingerprint_status_c.remove_css_class("success");
                 fingerprint_status_c.remove_css_class("error");
+            } else if let Some(active) = shared_c.selected_user(request_id) {
+                // Refused (e.g. a response is in flight); show the identity
+                // that is really being authenticated.
+                if active != selected {
+                    *initializing_c.borrow_mut() = true;
+                    dropdown.set_selected(active as u32);
+                    *initializing_c.borrow_mut() = false;
+                }
             }
         });
     }